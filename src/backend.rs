@@ -0,0 +1,160 @@
+use crate::{PCMMode, PCM_DATA_WIDTH};
+use crate::geometry::Geometry;
+use crate::display::{DisplayResolution, Image, RGB8};
+use crate::vsync::Vblank;
+use crate::error::Result;
+
+/// An output which shows the PCM fields on screen.
+pub trait Backend: Send {
+    /// Current resolution of the output, if there is one to detect the PCM mode from
+    fn get_resolution(&self) -> Option<DisplayResolution>;
+
+    /// Sets up the output for the mode, called from the draw thread before the first field
+    fn start(&mut self, mode: &PCMMode, geometry: &Geometry, colors: &[RGB8]) -> Result<()>;
+
+    /// Whether the output can tell when its vertical sync happens, available after start
+    fn has_vsync(&self) -> bool;
+
+    /// Blocks until the next vertical sync, with its count and time when the output has them
+    fn wait_for_vsync(&mut self) -> Result<Option<Vblank>>;
+
+    /// Image to compose the next field into, PCM_DATA_WIDTH wide and a row for every line
+    /// of the field, starting with the CTL line
+    fn back_buffer(&mut self) -> &mut Image;
+
+    /// Shows the field composed into the back buffer
+    fn present(&mut self) -> Result<()>;
+}
+
+/// Scales fields onto a linear framebuffer, the same way the dispmanx elements do:
+/// nearest neighbour horizontally and every line doubled for the two interlaced fields.
+pub struct FieldScaler {
+    bytes_per_pixel: usize,
+    colors: Vec<[u8; 4]>,
+    base_line: Vec<u8>,
+    column_pixels: Vec<i32>,
+    preamble_width: i32,
+    top_offset: i32,
+    height: i32
+}
+
+impl FieldScaler {
+    /// Colors are given as the bytes of the pixel format, in memory order
+    pub fn new(mode: &PCMMode, geometry: &Geometry, width: i32, height: i32, bytes_per_pixel: usize, colors: Vec<[u8; 4]>) -> Self {
+        // Which pixel of the PCM line every framebuffer column shows
+        let physical_pixel_width = geometry.physical_pixel_width(mode);
+        let left_offset = geometry.left_offset;
+        let column_pixels = (0..width.min(mode.screen_width))
+            .map(|x| if x < left_offset { -1 } else { (((x - left_offset) as f32 / physical_pixel_width) as i32).min(geometry.full_width - 1) })
+            .collect();
+
+        FieldScaler {
+            bytes_per_pixel: bytes_per_pixel,
+            colors: colors,
+            base_line: geometry.get_base_line(),
+            column_pixels: column_pixels,
+            preamble_width: geometry.preamble_width(),
+            top_offset: geometry.top_offset,
+            height: height
+        }
+    }
+
+    pub fn clear(&self, memory: &mut [u8]) {
+        let black = &self.colors[0][..self.bytes_per_pixel];
+        for pixel in memory.chunks_mut(self.bytes_per_pixel) {
+            pixel.copy_from_slice(black);
+        }
+    }
+
+    pub fn draw(&self, image: &Image, memory: &mut [u8], pitch: usize) {
+        let bytes_per_pixel = self.bytes_per_pixel;
+
+        for h in 0..image.height {
+            let row = image.get_row(h);
+            for y in [self.top_offset + h * 2, self.top_offset + h * 2 + 1].iter() {
+                if *y >= self.height { continue; }
+                let line = &mut memory[*y as usize * pitch..(*y as usize + 1) * pitch];
+
+                for (x, pixel) in self.column_pixels.iter().enumerate() {
+                    if *pixel < 0 { continue; }
+                    let data_pixel = *pixel - self.preamble_width;
+                    let color = if (0..PCM_DATA_WIDTH).contains(&data_pixel) { row[data_pixel as usize] } else { self.base_line[*pixel as usize] };
+                    line[x * bytes_per_pixel..(x + 1) * bytes_per_pixel].copy_from_slice(&self.colors[color as usize][..bytes_per_pixel]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::ImageType;
+    use crate::get_pcm_modes;
+
+    const UNTOUCHED: u8 = 0xff;
+
+    // The default picture: preamble 1010, white reference 02222, 137 cells from column 14
+    fn default_geometry() -> Geometry {
+        Geometry { left_offset: 14, top_offset: 1, full_width: 137, preamble: vec![1, 0, 1, 0], white_reference: vec![0, 2, 2, 2, 2] }
+    }
+
+    // Palette index n drawn as the byte n, one byte per pixel
+    fn index_colors(count: usize) -> Vec<[u8; 4]> {
+        (0..count).map(|n| [n as u8, 0, 0, 0]).collect()
+    }
+
+    #[test]
+    fn columns_map_to_line_cells() {
+        let mode = get_pcm_modes()[0];
+        let geometry = default_geometry();
+        let width = mode.screen_width as usize;
+        // Data cell n is palette index 3 + n, apart from the base line colors
+        let scaler = FieldScaler::new(&mode, &geometry, mode.screen_width, mode.screen_height, 1, index_colors(3 + PCM_DATA_WIDTH as usize));
+        let mut image = Image::new(ImageType::_8BPP, PCM_DATA_WIDTH, 1);
+        for (n, pixel) in image.get_row_mut(0).iter_mut().enumerate() {
+            *pixel = 3 + n as u8;
+        }
+
+        let mut memory = vec![UNTOUCHED; width * mode.screen_height as usize];
+        scaler.draw(&image, &mut memory, width);
+
+        // Every column right of the left offset shows the cell it falls in
+        let physical_pixel_width = geometry.physical_pixel_width(&mode);
+        let base_line = geometry.get_base_line();
+        for y in 1..3 {
+            let line = &memory[y * width..(y + 1) * width];
+            assert!(line[..14].iter().all(|p| *p == UNTOUCHED), "left offset drawn in row {}", y);
+
+            let mut cells = vec![];
+            for (x, pixel) in line.iter().enumerate().skip(14) {
+                let cell = ((x - 14) as f32 / physical_pixel_width) as usize;
+                let expected = if cell >= 4 && cell < 4 + PCM_DATA_WIDTH as usize { 3 + (cell - 4) as u8 } else { base_line[cell] };
+                assert_eq!(*pixel, expected, "column {} of row {}", x, y);
+                if cells.last() != Some(&cell) { cells.push(cell); }
+            }
+            // Each cell once, in order, across the whole width
+            assert_eq!(cells, (0..137).collect::<Vec<_>>());
+        }
+
+        // First data cell: 20.6 pixels of preamble
+        assert_eq!(memory[width + 34], 0);
+        assert_eq!(memory[width + 35], 3);
+        assert_eq!(memory[width + width - 1], 2);
+        assert!(memory[..width].iter().chain(memory[3 * width..].iter()).all(|p| *p == UNTOUCHED));
+    }
+
+    #[test]
+    fn columns_right_of_the_mode_are_left_alone() {
+        let mode = get_pcm_modes()[1];
+        let width = mode.screen_width as usize + 16;
+        let scaler = FieldScaler::new(&mode, &default_geometry(), width as i32, mode.screen_height, 1, index_colors(3));
+        let image = Image::new(ImageType::_8BPP, PCM_DATA_WIDTH, 1);
+
+        let mut memory = vec![UNTOUCHED; width * 3];
+        scaler.draw(&image, &mut memory, width);
+        let line = &memory[width..2 * width];
+        assert_eq!(line[mode.screen_width as usize - 1], 2);
+        assert!(line[mode.screen_width as usize..].iter().all(|p| *p == UNTOUCHED));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::PCMMode;
+use crate::encoder;
+use crate::error::{Error, Result};
+use crate::pcm::ControlWord;
+use crate::playlist::{Playlist, PlaylistItem};
+use crate::signal::{self, SignalGenerator};
+use crate::vsync;
+
+use std::io;
+
+const AUDIO_SAMPLE_RATE: f64 = 44100.0;
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+// Stereo samples of the item, or why it can't be played
+fn check_item(item: &PlaylistItem, control: ControlWord) -> Result<u64> {
+    encoder::get_item_control(control, item)?;
+
+    if signal::is_signal_item(&item.file) {
+        return Ok(SignalGenerator::new(&item.file).map_err(Error::Input)?.len());
+    }
+
+    let mut reader = hound::WavReader::open(&item.file).map_err(|e| match e {
+        hound::Error::IoError(ref error) if error.kind() == io::ErrorKind::NotFound => Error::Input(format!("{} is missing", item.file)),
+        e => Error::Input(format!("Cannot open WAV {}: {}", item.file, e))
+    })?;
+    encoder::check_wave_spec(&item.file, reader.spec())?;
+
+    // Read through the whole file, a truncated or damaged one would stop the encoder halfway
+    let mut samples = 0u64;
+    for sample in reader.samples::<i32>() {
+        if let Err(e) = sample {
+            return Err(Error::Input(format!("{} cannot be read after {}: {}", item.file, format_duration(samples as f64 / 2.0 / AUDIO_SAMPLE_RATE), e)));
+        }
+        samples += 1;
+    }
+    Ok(samples / 2)
+}
+
+/// Opens every item of the playlist the way playing it would and reads them through,
+/// reporting how long each plays and the total runtime, without touching the display.
+/// Fails when any item would be skipped.
+pub fn check_playlist(mut playlist: Playlist, control: ControlWord, mode: &PCMMode, drift_correction: bool) -> Result<()> {
+    // Every line carries three stereo samples
+    let (numerator, denominator) = vsync::get_exact_field_rate(mode);
+    let playback_rate = (mode.pcm_data_lines_in_field * 3) as f64 * numerator as f64 / denominator as f64;
+    let speed = playback_rate / AUDIO_SAMPLE_RATE;
+    if (speed - 1.0).abs() < 1e-6 {
+        println!("Audio: plays at {:.0} Hz", playback_rate);
+    } else if drift_correction {
+        println!("Audio: resampled to {:.0} Hz by --drift-correction", playback_rate);
+    } else {
+        println!("Audio: plays at {:.0} Hz, {:.2}% {} than recorded, --drift-correction resamples it", playback_rate,
+            (speed - 1.0).abs() * 100.0, if speed < 1.0 { "slower" } else { "faster" });
+    }
+
+    let items = playlist.len();
+    let mut runtime = 0.0;
+    let mut failed = 0;
+    for index in 1..=items {
+        let item = playlist.next_item();
+        match check_item(&item, control) {
+            Ok(samples) => {
+                let seconds = samples as f64 / AUDIO_SAMPLE_RATE;
+                // Without resampling the audio plays at the rate of the field clock
+                let playing = if drift_correction { seconds } else { seconds / speed };
+                runtime += playing;
+                println!("Item {}: {}: {}", index, item.file, format_duration(playing));
+            },
+            Err(error) => {
+                failed += 1;
+                println!("Item {}: {}", index, error);
+            }
+        }
+    }
+
+    println!("Runtime: {} for {} of {} item(s), then the playlist starts over", format_duration(runtime), items - failed, items);
+    if failed > 0 {
+        return Err(Error::Input(format!("{} of the {} playlist item(s) cannot be played", failed, items)));
+    }
+    println!("Check passed");
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use crate::error::{Error, Result};
+
+use serde::Deserialize;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+pub const DEFAULT_CONFIG_PATH: &'static str = "/etc/picm.toml";
+
+/// Settings bundled for a deck setup, anything given on the command line overrides them.
+/// Keys are the long option names, e.g. left-offset = 14.
+#[derive(Deserialize, Default, Clone)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Profile {
+    pub input: Option<String>,
+    pub backend: Option<String>,
+    pub fb_device: Option<String>,
+    pub drm_device: Option<String>,
+    pub software_vsync: Option<bool>,
+    pub drift_correction: Option<bool>,
+    pub metrics: Option<String>,
+    pub metrics_format: Option<String>,
+    pub preload: Option<String>,
+    pub preload_budget: Option<usize>,
+    pub draw_sched: Option<String>,
+    pub producer_sched: Option<String>,
+    pub control_sched: Option<String>,
+    pub draw_cpus: Option<String>,
+    pub producer_cpus: Option<String>,
+    pub control_cpus: Option<String>,
+    pub lock_memory: Option<bool>,
+    pub pre_emphasis: Option<bool>,
+    pub ctl_flags: Option<String>,
+    pub mode: Option<String>,
+    pub screen_width: Option<i32>,
+    pub screen_height: Option<i32>,
+    pub field_rate: Option<i32>,
+    pub left_offset: Option<i32>,
+    pub top_offset: Option<i32>,
+    pub line_width: Option<i32>,
+    pub preamble: Option<String>,
+    pub white_reference: Option<String>,
+    pub lines_in_field: Option<i32>,
+    pub visible_lines: Option<i32>,
+    pub black_level: Option<u8>,
+    pub data_level: Option<u8>,
+    pub white_level: Option<u8>
+}
+
+/// A custom mode which can be picked by name with --mode, like [mode.pal-overscan]
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ModeDefinition {
+    pub screen_width: i32,
+    pub screen_height: i32,
+    pub field_rate: i32,
+    pub lines_in_field: i32,
+    /// Lines shown in a field including the control line, half the screen height if left out
+    pub visible_lines: Option<i32>
+}
+
+/// Profiles are tables named like [profile.vhs-pal], modes like [mode.pal-overscan]
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Profile to use when none is picked on the command line
+    pub default_profile: Option<String>,
+    pub profile: BTreeMap<String, Profile>,
+    pub mode: BTreeMap<String, ModeDefinition>
+}
+
+impl Config {
+    /// Reads the given config file, or the system wide one if there is one
+    pub fn load(path: Option<&String>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.clone(),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => String::from(DEFAULT_CONFIG_PATH),
+            None => return Ok(Config::default())
+        };
+
+        let contents = fs::read_to_string(&path).map_err(|e| Error::Usage(format!("Cannot read config file {}: {}", path, e)))?;
+        toml::from_str(&contents).map_err(|e| Error::Usage(format!("Invalid config file {}: {}", path, e)))
+    }
+
+    /// Name and settings of the picked profile, falling back to the default one
+    pub fn get_profile(&self, name: Option<&String>) -> Result<Option<(&String, &Profile)>> {
+        let name = match name.or(self.default_profile.as_ref()) {
+            Some(name) => name,
+            None => return Ok(None)
+        };
+        match self.profile.get_key_value(name) {
+            Some(profile) => Ok(Some(profile)),
+            None => Err(Error::Usage(format!("Unknown profile: {}, available: {}", name, self.profile.keys().cloned().collect::<Vec<String>>().join(", "))))
+        }
+    }
+}
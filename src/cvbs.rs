@@ -0,0 +1,254 @@
+use crate::PCMMode;
+use crate::geometry::Geometry;
+use crate::levels::Levels;
+use crate::encoder::LineSource;
+use crate::error::{Error, Result};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+// Framebuffer pixels are clocked out at the BT.601 rate
+const PIXEL_CLOCK_MHZ: f64 = 13.5;
+
+#[derive(Copy, Clone, PartialEq)]
+enum HalfLine {
+    Equalizing,
+    Broad,
+    Blank
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum LineKind {
+    Video,
+    VideoThen(HalfLine),
+    Blank,
+    Pulses(HalfLine, HalfLine)
+}
+
+pub struct VideoStandard {
+    pub name: &'static str,
+    pub lines: i32,
+    pub line_us: f64,
+    pub subcarrier_hz: f64,
+    hsync_us: f64,
+    equalizing_us: f64,
+    serration_us: f64,
+    /// Leading edge of the horizontal sync to the first framebuffer pixel
+    active_start_us: f64,
+    sync_mv: f64,
+    black_mv: f64,
+    white_mv: f64,
+    /// First frame lines of the two fields showing framebuffer content
+    active_lines: [i32; 2],
+    line_kind: fn(i32) -> LineKind
+}
+
+fn pal_line_kind(line: i32) -> LineKind {
+    use HalfLine::*;
+    match line {
+        1 | 2 => LineKind::Pulses(Broad, Broad),
+        3 => LineKind::Pulses(Broad, Equalizing),
+        4 | 5 => LineKind::Pulses(Equalizing, Equalizing),
+        6..=22 => LineKind::Blank,
+        23..=310 => LineKind::Video,
+        311 | 312 => LineKind::Pulses(Equalizing, Equalizing),
+        313 => LineKind::Pulses(Equalizing, Broad),
+        314 | 315 => LineKind::Pulses(Broad, Broad),
+        316 | 317 => LineKind::Pulses(Equalizing, Equalizing),
+        318 => LineKind::Pulses(Equalizing, Blank),
+        319..=334 => LineKind::Blank,
+        335..=622 => LineKind::Video,
+        623 => LineKind::VideoThen(Equalizing),
+        _ => LineKind::Pulses(Equalizing, Equalizing)
+    }
+}
+
+fn ntsc_line_kind(line: i32) -> LineKind {
+    use HalfLine::*;
+    match line {
+        1..=3 => LineKind::Pulses(Equalizing, Equalizing),
+        4..=6 => LineKind::Pulses(Broad, Broad),
+        7..=9 => LineKind::Pulses(Equalizing, Equalizing),
+        10..=20 => LineKind::Blank,
+        21..=262 => LineKind::Video,
+        263 => LineKind::VideoThen(Equalizing),
+        264 | 265 => LineKind::Pulses(Equalizing, Equalizing),
+        266 => LineKind::Pulses(Equalizing, Broad),
+        267 | 268 => LineKind::Pulses(Broad, Broad),
+        269 => LineKind::Pulses(Broad, Equalizing),
+        270 | 271 => LineKind::Pulses(Equalizing, Equalizing),
+        272 => LineKind::Pulses(Equalizing, Blank),
+        273..=282 => LineKind::Blank,
+        _ => LineKind::Video
+    }
+}
+
+const PAL: VideoStandard = VideoStandard {
+    name: "PAL", lines: 625, line_us: 64.0, subcarrier_hz: 4433618.75,
+    hsync_us: 4.7, equalizing_us: 2.35, serration_us: 4.7, active_start_us: 132.0 / PIXEL_CLOCK_MHZ,
+    sync_mv: -300.0, black_mv: 0.0, white_mv: 700.0,
+    active_lines: [23, 335],
+    line_kind: pal_line_kind
+};
+
+const NTSC: VideoStandard = VideoStandard {
+    name: "NTSC", lines: 525, line_us: 63.5556, subcarrier_hz: 3579545.45,
+    hsync_us: 4.7, equalizing_us: 2.3, serration_us: 4.7, active_start_us: 122.0 / PIXEL_CLOCK_MHZ,
+    sync_mv: -285.7, black_mv: 53.6, white_mv: 714.3,
+    active_lines: [22, 285],
+    line_kind: ntsc_line_kind
+};
+
+impl VideoStandard {
+    pub fn for_mode(mode: &PCMMode) -> &'static VideoStandard {
+        if mode.field_rate == 50 { &PAL } else { &NTSC }
+    }
+
+    /// Parses a sample rate given in Hz, or "4fsc" for four times the color subcarrier
+    pub fn parse_sample_rate(&self, rate: &str) -> Option<f64> {
+        if rate == "4fsc" {
+            Some(self.subcarrier_hz * 4.0)
+        } else {
+            rate.parse::<f64>().ok().filter(|r| *r > 0.0)
+        }
+    }
+}
+
+/// Synthesizes the composite signal the Pi would put out for the encoded fields and
+/// writes it as raw signed 16 bit little endian samples, 32 LSB per mV, blanking at 0.
+pub struct CvbsWriter {
+    mode: PCMMode,
+    geometry: Geometry,
+    standard: &'static VideoStandard,
+    sample_rate: f64,
+    levels_mv: [f64; 3],
+    physical_pixel_width: f64,
+    output: BufWriter<File>,
+    line_index: u64,
+    samples_written: u64
+}
+
+impl CvbsWriter {
+    pub fn create(file: &String, mode: PCMMode, geometry: Geometry, levels: Levels, sample_rate: f64) -> Result<Self> {
+        let standard = VideoStandard::for_mode(&mode);
+        let level_mv = |luma: f32| standard.black_mv + (standard.white_mv - standard.black_mv) * luma as f64;
+        let lumas = levels.get_lumas();
+
+        let output = File::create(file).map_err(|e| Error::Output(format!("Cannot create CVBS output file {}: {}", file, e)))?;
+
+        Ok(CvbsWriter {
+            mode: mode,
+            physical_pixel_width: geometry.physical_pixel_width(&mode) as f64,
+            geometry: geometry,
+            standard: standard,
+            sample_rate: sample_rate,
+            levels_mv: [level_mv(lumas[0]), level_mv(lumas[1]), level_mv(lumas[2])],
+            output: BufWriter::new(output),
+            line_index: 0,
+            samples_written: 0
+        })
+    }
+
+    pub fn print_timing(&self) {
+        let bit_cell_us = self.physical_pixel_width / PIXEL_CLOCK_MHZ;
+        println!("Standard: {}, {} lines, {:.4} us per line", self.standard.name, self.standard.lines, self.standard.line_us);
+        println!("Sample rate: {:.0} Hz, {:.2} samples per line", self.sample_rate, self.standard.line_us * self.sample_rate / 1e6);
+        println!("PCM line: {} bits over {:.3} us", self.geometry.full_width, self.geometry.full_width as f64 * bit_cell_us);
+        println!("Bit cell: {:.2} ns ({:.4} Mbit/s), {:.2} samples", bit_cell_us * 1000.0, 1.0 / bit_cell_us, bit_cell_us * self.sample_rate / 1e6);
+        println!("Framebuffer quantizes bit cells to {} or {} pixels ({:.2} / {:.2} ns)",
+            self.physical_pixel_width.floor(), self.physical_pixel_width.ceil(),
+            self.physical_pixel_width.floor() * 1000.0 / PIXEL_CLOCK_MHZ, self.physical_pixel_width.ceil() * 1000.0 / PIXEL_CLOCK_MHZ);
+    }
+
+    fn pulse_level(&self, half: HalfLine, t: f64) -> f64 {
+        let half_line_us = self.standard.line_us / 2.0;
+        match half {
+            HalfLine::Equalizing if t < self.standard.equalizing_us => self.standard.sync_mv,
+            HalfLine::Broad if t < half_line_us - self.standard.serration_us => self.standard.sync_mv,
+            _ => 0.0
+        }
+    }
+
+    fn video_level(&self, pixel_bytes: &Option<Vec<u8>>, t: f64) -> f64 {
+        if t < self.standard.hsync_us { return self.standard.sync_mv; }
+
+        let x = ((t - self.standard.active_start_us) * PIXEL_CLOCK_MHZ).floor() as i32;
+        if x < 0 || x >= self.mode.screen_width { return 0.0; }
+
+        match pixel_bytes {
+            Some(pixel_bytes) if x >= self.geometry.left_offset => {
+                let pixel = ((x - self.geometry.left_offset) as f64 / self.physical_pixel_width) as usize;
+                self.levels_mv[pixel_bytes[pixel.min(pixel_bytes.len() - 1)] as usize]
+            },
+            _ => self.levels_mv[0]
+        }
+    }
+
+    fn write_line(&mut self, kind: LineKind, pixel_bytes: Option<Vec<u8>>) -> io::Result<()> {
+        let line_us = self.standard.line_us;
+        let half_line_us = line_us / 2.0;
+
+        // Derive the sample range from the absolute line count so non integer ratios don't drift
+        let start = (self.line_index as f64 * line_us * self.sample_rate / 1e6).round() as u64;
+        let end = ((self.line_index + 1) as f64 * line_us * self.sample_rate / 1e6).round() as u64;
+
+        for sample in start..end {
+            let t = sample as f64 * 1e6 / self.sample_rate - self.line_index as f64 * line_us;
+            let level_mv = match kind {
+                LineKind::Video => self.video_level(&pixel_bytes, t),
+                LineKind::VideoThen(half) => if t < half_line_us { self.video_level(&pixel_bytes, t) } else { self.pulse_level(half, t - half_line_us) },
+                LineKind::Blank => if t < self.standard.hsync_us { self.standard.sync_mv } else { 0.0 },
+                LineKind::Pulses(first, second) => if t < half_line_us { self.pulse_level(first, t) } else { self.pulse_level(second, t - half_line_us) }
+            };
+
+            let value = (level_mv * 32.0).round() as i16;
+            self.output.write_all(&value.to_le_bytes())?;
+        }
+
+        self.samples_written += end - start;
+        self.line_index += 1;
+        Ok(())
+    }
+
+    /// Writes a whole frame, showing one PCM field in each of the two video fields
+    pub fn write_frame(&mut self, fields: [Vec<u128>; 2]) -> io::Result<()> {
+        for line in 1..=self.standard.lines {
+            let kind = (self.standard.line_kind)(line);
+            if kind == LineKind::Blank || matches!(kind, LineKind::Pulses(_, _)) {
+                self.write_line(kind, None)?;
+                continue;
+            }
+
+            let field = if line < self.standard.active_lines[1] { 0 } else { 1 };
+            // Every field shows every other framebuffer row, PCM rows are doubled
+            let framebuffer_row = (line - self.standard.active_lines[field]) * 2 + field as i32;
+            let top_offset = self.geometry.top_offset;
+            let pcm_row = (framebuffer_row - top_offset) / 2;
+
+            let pixel_bytes = if framebuffer_row >= top_offset && pcm_row < fields[field].len() as i32 {
+                Some(self.geometry.get_line_pixel_bytes(fields[field][pcm_row as usize]))
+            } else {
+                None
+            };
+            self.write_line(kind, pixel_bytes)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.output.flush()?;
+        println!("Wrote {} samples ({:.2} s)", self.samples_written, self.samples_written as f64 / self.sample_rate);
+        Ok(())
+    }
+}
+
+/// Writes the fields as frames of two, the number of fields needs to be even
+pub fn render(source: &mut dyn LineSource, writer: &mut CvbsWriter, mode: &PCMMode, fields: u64) -> Result<()> {
+    let write_error = |e: io::Error| Error::Output(format!("Cannot write CVBS output file: {}", e));
+    for _ in 0..fields / 2 {
+        let first = source.next_field_lines(mode)?;
+        let second = source.next_field_lines(mode)?;
+        writer.write_frame([first, second]).map_err(write_error)?;
+    }
+    writer.finish().map_err(write_error)
+}
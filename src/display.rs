@@ -1,9 +1,17 @@
+use crate::{PCMMode, PCM_DATA_WIDTH};
+use crate::geometry::Geometry;
+use crate::backend::Backend;
+use crate::vsync::Vblank;
+use crate::error::{Error, Result};
+
 use videocore::{bcm_host, dispmanx, image::ImageType as VCImageType, image::Rect as VCRect, display::InputFormat};
 use std::ffi::{c_void, CString};
 use std::ptr;
 use std::thread;
 use std::os::raw::c_char;
 
+const DISPMANX_LAYER: i32 = 200;
+
 const NO_ALPHA: dispmanx::VCAlpha = dispmanx::VCAlpha { flags: dispmanx::FlagsAlpha::FIXED_ALL_PIXELS, opacity: 255, mask: 0 };
 
 #[cfg(target_arch = "arm")]
@@ -44,8 +52,8 @@ pub struct Display {
     handle: dispmanx::DisplayHandle
 }
 
-pub struct VSyncData<'t> {
-    pub draw_thread: &'t thread::Thread
+pub struct VSyncData {
+    pub draw_thread: thread::Thread
 }
 
 extern "C" fn vsync_callback(_: dispmanx::UpdateHandle, arg: *mut c_void) {
@@ -60,13 +68,17 @@ fn get_c_string(text: &'static str) -> CString {
 }
 
 impl<'d> Display {
-    pub fn init(display: u32) -> Self {
+    pub fn init(display: u32) -> Result<Self> {
         bcm_host::init();
         let disp_handle = dispmanx::display_open(display);
+        // DISPMANX_NO_HANDLE
+        if disp_handle == 0 {
+            return Err(Error::Display(format!("Cannot open dispmanx display {}", display)));
+        }
 
-        Display {
+        Ok(Display {
             handle: disp_handle
-        }
+        })
     }
 
     pub fn set_bilinear_filtering(&'d self, enabled: bool) {
@@ -136,6 +148,13 @@ pub struct RGB8 {
     pub b: u8
 }
 
+impl RGB8 {
+    /// Relative luminance (0.0 - 1.0) the composite encoder turns this color into
+    pub fn luma(&self) -> f32 {
+        (0.299 * self.r as f32 + 0.587 * self.g as f32 + 0.114 * self.b as f32) / 255.0
+    }
+}
+
 pub struct Palette {
     data: Vec<u16>
 }
@@ -191,16 +210,26 @@ impl Image {
         }
     }
 
-    pub fn set_pixel_bytes(&mut self, x: i32, y: i32, bytes: &Vec<u8>) {
+    pub fn set_pixel_bytes(&mut self, x: i32, y: i32, bytes: &[u8]) {
         match self.image_type {
             ImageType::_8BPP => {
                 let offset = (x + y * self.pitch) as usize;
-                let end = offset + bytes.len() as usize;
-                self.data.splice(offset..end, bytes.clone().into_iter());
+                self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
             }
         }
     }
 
+    pub fn get_row(&self, y: i32) -> &[u8] {
+        let offset = (y * self.pitch) as usize;
+        &self.data[offset..offset + self.width as usize]
+    }
+
+    /// To compose a row in place
+    pub fn get_row_mut(&mut self, y: i32) -> &mut [u8] {
+        let offset = (y * self.pitch) as usize;
+        &mut self.data[offset..offset + self.width as usize]
+    }
+
     pub fn get_data_ptr(&mut self) -> *mut c_void {
         self.data.as_mut_ptr() as *mut c_void
     }
@@ -235,4 +264,98 @@ impl<'a> ImageResource {
         dispmanx::resource_write_data(self.resource, image_type_to_vc_image_type(self.image.image_type), self.image.pitch, self.image.get_data_ptr(), &rect);
     }
 
-}
\ No newline at end of file
+}
+
+pub struct DispmanxBackend {
+    display: Display,
+    vsync_data: Option<Box<VSyncData>>,
+    sync_frame_resource: Option<ImageResource>,
+    data_resources: Vec<ImageResource>,
+    data_element: Option<Element>,
+    next_resource: usize
+}
+
+impl DispmanxBackend {
+    pub fn init(display: u32) -> Result<Self> {
+        Ok(DispmanxBackend {
+            display: Display::init(display)?,
+            vsync_data: None,
+            sync_frame_resource: None,
+            data_resources: Vec::new(),
+            data_element: None,
+            next_resource: 1
+        })
+    }
+}
+
+impl Backend for DispmanxBackend {
+    fn get_resolution(&self) -> Option<DisplayResolution> {
+        Some(self.display.get_resolution())
+    }
+
+    fn start(&mut self, mode: &PCMMode, geometry: &Geometry, colors: &[RGB8]) -> Result<()> {
+        self.display.set_bilinear_filtering(false);
+
+        let update = self.display.start_update(10);
+
+        let mut palette = Palette::from_colors(Vec::from(colors));
+
+        // Synchronization frame
+        let mut sync_frame_resource = ImageResource::from_image(Image::new(ImageType::_8BPP, geometry.full_width, 1));
+        sync_frame_resource.set_palette(&mut palette);
+        let base_line = geometry.get_base_line();
+        sync_frame_resource.image.set_pixel_bytes(0, 0, &base_line);
+        sync_frame_resource.update();
+
+        let frame_rect = Rect { x: geometry.left_offset, y: geometry.top_offset, width: mode.screen_width - geometry.left_offset, height: (mode.visible_pcm_field_height * 2) };
+        update.create_element_from_image_resource(DISPMANX_LAYER, frame_rect, &sync_frame_resource);
+
+        // Data front and back buffer image resource
+        for _ in 0..2 {
+            let mut resource = ImageResource::from_image(Image::new(ImageType::_8BPP, PCM_DATA_WIDTH, mode.visible_pcm_field_height));
+            resource.set_palette(&mut palette);
+            resource.update();
+            self.data_resources.push(resource);
+        }
+
+        let physical_pixel_width = geometry.physical_pixel_width(mode);
+        let data_physical_left_offset = (geometry.left_offset as f32 + physical_pixel_width * geometry.preamble_width() as f32).round() as i32;
+        let data_physical_width = (physical_pixel_width * PCM_DATA_WIDTH as f32).round() as i32;
+
+        let data_rect = Rect { x: data_physical_left_offset, y: geometry.top_offset, width: data_physical_width, height: (mode.visible_pcm_field_height * 2) };
+        self.data_element = Some(update.create_element_from_image_resource(DISPMANX_LAYER + 1, data_rect, &self.data_resources[0]));
+        update.submit_sync();
+
+        self.sync_frame_resource = Some(sync_frame_resource);
+
+        // VSync handler wakes the draw thread up
+        let mut vsync_data = Box::new(VSyncData { draw_thread: thread::current() });
+        self.display.start_vsync_handler(&mut vsync_data);
+        self.vsync_data = Some(vsync_data);
+        Ok(())
+    }
+
+    fn has_vsync(&self) -> bool {
+        true
+    }
+
+    fn wait_for_vsync(&mut self) -> Result<Option<Vblank>> {
+        thread::park();
+        Ok(None)
+    }
+
+    fn back_buffer(&mut self) -> &mut Image {
+        &mut self.data_resources[self.next_resource].image
+    }
+
+    fn present(&mut self) -> Result<()> {
+        let update = self.display.start_update(10);
+
+        self.data_resources[self.next_resource].update();
+        update.replace_element_source(self.data_element.as_ref().unwrap(), &self.data_resources[self.next_resource]);
+        update.submit();
+
+        self.next_resource = if self.next_resource == 1 { 0 } else { 1 };
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+const SAMPLE_RATE: f64 = 44100.0;
+const ZERO_TIME_CONSTANT: f64 = 50e-6;
+const POLE_TIME_CONSTANT: f64 = 15e-6;
+
+/// 50/15 us pre-emphasis, the shelf decks with de-emphasis expect: unity gain at low
+/// frequencies rising towards +10.5 dB at the top. Loud high frequency content clips, so
+/// material needs headroom for it.
+pub struct PreEmphasis {
+    b: [f64; 2],
+    pole: f64,
+    state: [[f64; 2]; 2],
+    clipped: u64
+}
+
+impl PreEmphasis {
+    pub fn new() -> Self {
+        // Matched z-transform of (1 + s * 50us) / (1 + s * 15us), normalized to unity gain at
+        // DC. Stays within 0.2 dB of the analog curve up to 16 kHz.
+        let zero = (-1.0 / (ZERO_TIME_CONSTANT * SAMPLE_RATE)).exp();
+        let pole = (-1.0 / (POLE_TIME_CONSTANT * SAMPLE_RATE)).exp();
+        let gain = (1.0 - pole) / (1.0 - zero);
+
+        PreEmphasis {
+            b: [gain, -gain * zero],
+            pole: pole,
+            state: [[0.0; 2]; 2],
+            clipped: 0
+        }
+    }
+
+    pub fn process(&mut self, stereo_sample: [u16; 2]) -> [u16; 2] {
+        let mut result = [0u16; 2];
+
+        for c in 0..2 {
+            let input = stereo_sample[c] as i16 as f64;
+            let [previous_input, previous_output] = self.state[c];
+            let output = self.b[0] * input + self.b[1] * previous_input + self.pole * previous_output;
+            self.state[c] = [input, output];
+
+            if output > i16::MAX as f64 || output < i16::MIN as f64 { self.clipped += 1; }
+            result[c] = output.round().max(i16::MIN as f64).min(i16::MAX as f64) as i16 as u16;
+        }
+
+        result
+    }
+
+    /// Samples clipped since the last call
+    pub fn take_clipped(&mut self) -> u64 {
+        let clipped = self.clipped;
+        self.clipped = 0;
+        clipped
+    }
+}
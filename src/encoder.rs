@@ -0,0 +1,452 @@
+use crate::PCMMode;
+use crate::pcm::{self, ControlWord, PCMEngine};
+use crate::playlist::{Playlist, PlaylistItem};
+use crate::resample::Resampler;
+use crate::vsync::RateCorrection;
+use crate::signal::{self, SignalGenerator};
+use crate::emphasis::PreEmphasis;
+use crate::preload::{PreloadSettings, Preloader, PreloadedSamples};
+use crate::error::{Error, Result};
+use crate::fields::{self, Field};
+
+use std::{io, fs, iter, mem};
+use std::sync::Arc;
+
+type WavSamples = hound::WavIntoSamples<io::BufReader<fs::File>, i32>;
+
+enum AudioSource {
+    Wave(WavSamples),
+    Signal(SignalGenerator),
+    /// Decoded into RAM ahead of time, and the position in it
+    Preloaded(Arc<PreloadedSamples>, usize),
+    /// Played out, let go of before the next item is asked for
+    Ended
+}
+
+// Where the playlist items are read from
+enum Items {
+    Storage(Playlist),
+    Preloaded(Preloader)
+}
+
+impl Items {
+    fn len(&self) -> usize {
+        match self {
+            Items::Storage(playlist) => playlist.len(),
+            Items::Preloaded(preloader) => preloader.len()
+        }
+    }
+}
+
+/// Hands out the PCM lines to show, in the order they go on screen
+pub trait LineSource: Send {
+    fn next_line(&mut self) -> Result<u128>;
+
+    /// CTL line describing the lines handed out
+    fn ctl_line(&self) -> u128 {
+        ControlWord::new().to_line()
+    }
+
+    /// Takes a whole field into lines, replacing what was there: the CTL line followed by
+    /// the data lines which fit on the screen. Lines beyond the visible area are taken too,
+    /// but dropped. Fails when the source can't go on at all.
+    fn take_field(&mut self, mode: &PCMMode, lines: &mut Vec<u128>) -> Result<()> {
+        lines.clear();
+        lines.push(self.ctl_line());
+
+        for current_line in 0..mode.pcm_data_lines_in_field {
+            let line_data = self.next_line()?;
+            if current_line < mode.visible_pcm_data_field_height {
+                lines.push(line_data);
+            }
+        }
+        Ok(())
+    }
+
+    fn next_field_lines(&mut self, mode: &PCMMode) -> Result<Vec<u128>> {
+        let mut lines: Vec<u128> = Vec::with_capacity(mode.visible_pcm_field_height as usize);
+        self.take_field(mode, &mut lines)?;
+        Ok(lines)
+    }
+
+    /// Takes a whole field like take_field, along with the same field as it goes on after
+    /// silence and the silence carrying on from it, for the draw thread to show when the
+    /// field after it is late. Sources without an interleave to carry on have plain silence.
+    fn take_field_with_silence(&mut self, mode: &PCMMode, field: &mut Field) -> Result<()> {
+        self.take_field(mode, &mut field.lines)?;
+        field.after_silence.clear();
+        field.after_silence.extend_from_slice(&field.lines);
+        fields::fill_silence(field.lines[0], mode, &mut field.silence);
+        Ok(())
+    }
+
+    /// Name of what is being encoded, and how far in it is in seconds
+    fn now_playing(&self) -> Option<(&str, f64)> {
+        None
+    }
+}
+
+fn encode_field<I: Iterator<Item = [u16; 2]>>(pcm: &mut PCMEngine, control: ControlWord, samples: I, mode: &PCMMode, lines: &mut Vec<u128>) {
+    lines.clear();
+    lines.push(control.to_line());
+
+    let mut current_line = 0;
+    for stereo_sample in samples {
+        if let Some(line_data) = pcm.submit_stereo_sample(stereo_sample) {
+            if current_line < mode.visible_pcm_data_field_height {
+                lines.push(line_data);
+            }
+            current_line += 1;
+        }
+    }
+}
+
+// The field as it goes on after silence: encoded again from the flushed engine up to where
+// the words of the lines before it run out, the same lines as encoded from there on
+fn encode_after_silence(flushed: &mut PCMEngine, control: ControlWord, samples: &[[u16; 2]], mode: &PCMMode, lines: &[u128], after_silence: &mut Vec<u128>) {
+    encode_field(flushed, control, samples[..pcm::INTERLEAVE_SPAN * 3].iter().copied(), mode, after_silence);
+    after_silence.extend_from_slice(&lines[after_silence.len()..]);
+}
+
+// Silence through a copy of the engine, the lines after the words of the field run out are
+// the same flat silence
+fn encode_silence(pcm: &mut PCMEngine, control: ControlWord, mode: &PCMMode, silence: &mut Vec<u128>) {
+    let samples = iter::repeat([0u16; 2]).take(pcm::INTERLEAVE_SPAN * 3);
+    encode_field(pcm, control, samples, mode, silence);
+    silence.resize(mode.visible_pcm_field_height as usize, pcm::add_crc_to_data(0));
+}
+
+fn next_stereo_samples(source: &mut AudioSource) -> Option<[u16; 2]> {
+    let wav_samples = match source {
+        AudioSource::Wave(wav_samples) => wav_samples,
+        AudioSource::Signal(generator) => return generator.next_stereo_samples(),
+        AudioSource::Preloaded(samples, position) => {
+            *position += 1;
+            return samples.get(*position - 1);
+        },
+        AudioSource::Ended => return None
+    };
+    let mut result = [0u16; 2];
+
+    for i in 0..2 {
+        match wav_samples.next() {
+            Some(Ok(sample)) => {
+                result[i] = sample as u16;
+            },
+            Some(Err(error)) => {
+                println!("Warning: cannot read the WAV any further, skipping the rest of it: {}", error);
+                return None;
+            },
+            None => return None
+        }
+    }
+
+    Some(result)
+}
+
+pub fn open_wave(file: String) -> Result<WavSamples> {
+    println!("Opening WAV: {}", file);
+    let reader = hound::WavReader::open(&file).map_err(|e| Error::Input(format!("Cannot open WAV {}: {}", file, e)))?;
+    check_wave_spec(&file, reader.spec())?;
+    Ok(reader.into_samples::<i32>())
+}
+
+/// Fails for anything but 44.1kHz stereo 16 bit, the only format the encoder takes
+pub fn check_wave_spec(file: &str, spec: hound::WavSpec) -> Result<()> {
+    if spec.bits_per_sample != 16 || spec.sample_format != hound::SampleFormat::Int || spec.sample_rate != 44100 || spec.channels != 2 {
+        return Err(Error::Input(format!("{} is {} Hz, {} channel(s), {} bit: only 44.1kHz stereo 16 bit WAV files are supported",
+            file, spec.sample_rate, spec.channels, spec.bits_per_sample)));
+    }
+    Ok(())
+}
+
+fn open_item(item: String) -> Result<AudioSource> {
+    if signal::is_signal_item(&item) {
+        let generator = SignalGenerator::new(&item).map_err(Error::Input)?;
+        println!("Generating test signal: {}", item);
+        Ok(AudioSource::Signal(generator))
+    } else {
+        Ok(AudioSource::Wave(open_wave(item)?))
+    }
+}
+
+fn open_next_item(items: &mut Items, control: ControlWord) -> Result<(PlaylistItem, ControlWord, AudioSource)> {
+    let (item, samples) = match items {
+        Items::Storage(playlist) => (playlist.next_item(), None),
+        Items::Preloaded(preloader) => {
+            let preloaded = preloader.next_item()?;
+            (preloaded.item, preloaded.samples)
+        }
+    };
+
+    let item_control = get_item_control(control, &item)?;
+    let source = match samples {
+        Some(samples) => AudioSource::Preloaded(samples, 0),
+        None => open_item(item.file.clone())?
+    };
+    Ok((item, item_control, source))
+}
+
+// The next playlist item which can be played, the ones which can't are skipped with a warning
+fn next_item(items: &mut Items, control: ControlWord) -> Result<(PlaylistItem, ControlWord, AudioSource)> {
+    for _ in 0..items.len() {
+        match open_next_item(items, control) {
+            Ok(next) => return Ok(next),
+            Err(error) => println!("Warning: skipping playlist item: {}", error)
+        }
+    }
+    Err(Error::Input(format!("None of the {} playlist item(s) can be played", items.len())))
+}
+
+pub fn get_item_control(control: ControlWord, item: &PlaylistItem) -> Result<ControlWord> {
+    let mut item_control = control;
+    if let Some(flags) = &item.ctl_flags {
+        item_control.apply_flags(flags).map_err(|e| Error::Input(format!("Invalid CTL flags for {}: {}", item.file, e)))?;
+    }
+    Ok(item_control)
+}
+
+// Feeds the playlist through the PCM engine and hands out the encoded lines
+pub struct LineEncoder {
+    items: Items,
+    source: AudioSource,
+    control: ControlWord,
+    item_control: ControlWord,
+    item: PlaylistItem,
+    item_samples: u64,
+    resampler: Option<Resampler>,
+    emphasis: Option<PreEmphasis>,
+    /// The pre-emphasis filter runs for the current item
+    emphasize_item: bool,
+    pcm: PCMEngine,
+    field_samples: Vec<[u16; 2]>
+}
+
+impl LineEncoder {
+    /// The control word goes into the CTL line, playlist items can change it for themselves.
+    /// With preload settings the playlist is decoded into RAM ahead of playing it.
+    pub fn new(playlist: Playlist, control: ControlWord, preload: Option<PreloadSettings>) -> Result<Self> {
+        let mut items = match preload {
+            Some(settings) => Items::Preloaded(Preloader::start(playlist, settings)),
+            None => Items::Storage(playlist)
+        };
+        let (item, item_control, source) = next_item(&mut items, control)?;
+
+        Ok(LineEncoder {
+            items: items,
+            source: source,
+            control: control,
+            item_control: item_control,
+            item: item,
+            item_samples: 0,
+            resampler: None,
+            emphasis: None,
+            emphasize_item: false,
+            pcm: PCMEngine::new(),
+            field_samples: vec![]
+        })
+    }
+
+    /// Resamples the audio by the ratio the field clock measurement asks for
+    pub fn set_rate_correction(&mut self, correction: RateCorrection) {
+        self.resampler = Some(Resampler::new(correction));
+    }
+
+    /// Applies 50/15us pre-emphasis to the audio, and flags it in the CTL line. Items flagged
+    /// with emphasis are already emphasized and play as they are, no-emphasis items aren't filtered.
+    pub fn set_pre_emphasis(&mut self) -> Result<()> {
+        self.emphasis = Some(PreEmphasis::new());
+        let item = self.item.clone();
+        self.start_item(item, self.item_control)
+    }
+
+    fn start_item(&mut self, item: PlaylistItem, item_control: ControlWord) -> Result<()> {
+        self.item_control = item_control;
+        self.emphasize_item = false;
+        if self.emphasis.is_some() && !item_control.pre_emphasis {
+            // The flags of the item are left to turn the emphasis off again
+            let emphasized = ControlWord { pre_emphasis: true, ..self.control };
+            self.emphasize_item = get_item_control(emphasized, &item)?.pre_emphasis;
+            self.item_control.pre_emphasis = self.emphasize_item;
+        }
+        self.item = item;
+        self.item_samples = 0;
+        Ok(())
+    }
+
+    fn read_samples(&mut self) -> Result<[u16; 2]> {
+        let mut items_ended = 0;
+        loop {
+            if let Some(stereo_sample) = next_stereo_samples(&mut self.source) {
+                self.item_samples += 1;
+                return Ok(stereo_sample);
+            }
+
+            if let Some(clipped) = self.emphasis.as_mut().map(|e| e.take_clipped()).filter(|c| *c > 0) {
+                println!("Pre-emphasis clipped {} samples", clipped);
+            }
+            // Went round the whole playlist without a sample
+            if items_ended > self.items.len() {
+                return Err(Error::Input(String::from("The playlist items have no audio in them")));
+            }
+            items_ended += 1;
+
+            // Move to next playlist item, the preloaded audio of this one makes room for it
+            self.source = AudioSource::Ended;
+            let (item, item_control, source) = next_item(&mut self.items, self.control)?;
+            self.start_item(item, item_control)?;
+            self.source = source;
+        }
+    }
+
+    fn next_samples(&mut self) -> Result<[u16; 2]> {
+        let samples = self.next_resampled_samples()?;
+        Ok(match &mut self.emphasis {
+            Some(emphasis) if self.emphasize_item => emphasis.process(samples),
+            _ => samples
+        })
+    }
+
+    fn next_resampled_samples(&mut self) -> Result<[u16; 2]> {
+        if self.resampler.is_none() {
+            return self.read_samples();
+        }
+
+        loop {
+            if let Some(samples) = self.resampler.as_mut().unwrap().pop() {
+                return Ok(samples);
+            }
+            let input = self.read_samples()?;
+            self.resampler.as_mut().unwrap().push(input);
+        }
+    }
+}
+
+impl LineSource for LineEncoder {
+    fn ctl_line(&self) -> u128 {
+        self.item_control.to_line()
+    }
+
+    fn next_line(&mut self) -> Result<u128> {
+        loop {
+            let samples = self.next_samples()?;
+            if let Some(line_data) = self.pcm.submit_stereo_sample(samples) {
+                return Ok(line_data);
+            }
+        }
+    }
+
+    fn take_field(&mut self, mode: &PCMMode, lines: &mut Vec<u128>) -> Result<()> {
+        let control = self.item_control;
+        let mut samples = mem::take(&mut self.field_samples);
+        samples.clear();
+        for _ in 0..mode.pcm_data_lines_in_field * 3 {
+            samples.push(self.next_samples()?);
+        }
+        encode_field(&mut self.pcm, control, samples.iter().copied(), mode, lines);
+
+        self.field_samples = samples;
+        Ok(())
+    }
+
+    fn take_field_with_silence(&mut self, mode: &PCMMode, field: &mut Field) -> Result<()> {
+        // The next item may take over the control word while the field is read
+        let control = self.item_control;
+        let mut flushed = self.pcm.flushed();
+        self.take_field(mode, &mut field.lines)?;
+
+        encode_after_silence(&mut flushed, control, &self.field_samples, mode, &field.lines, &mut field.after_silence);
+        encode_silence(&mut self.pcm.clone(), control, mode, &mut field.silence);
+        Ok(())
+    }
+
+    fn now_playing(&self) -> Option<(&str, f64)> {
+        Some((&self.item.file, self.item_samples as f64 / 44100.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_pcm_modes;
+    use std::hint::black_box;
+    use std::time::Instant;
+
+    fn tone_encoder() -> LineEncoder {
+        LineEncoder::new(Playlist::new_with_single_item(String::from("signal:sweep:100:10000:-6:10")), ControlWord::new(), None).unwrap()
+    }
+
+    #[test]
+    fn silence_carries_the_interleave_on() {
+        for mode in get_pcm_modes() {
+            let mut encoder = tone_encoder();
+            let mut reference = tone_encoder();
+            let mut fields: Vec<Field> = (0..3).map(|_| Field::new(&mode)).collect();
+            for field in fields.iter_mut() {
+                encoder.take_field_with_silence(&mode, field).unwrap();
+            }
+
+            // What the draw thread shows when the field after the first one is two fields late
+            let mut flat = vec![];
+            fields::fill_silence(fields[0].silence[0], &mode, &mut flat);
+            let shown = [&fields[0].lines, &fields[0].silence, &flat, &fields[1].after_silence, &fields[2].lines];
+
+            // The same audio with the silence encoded in between
+            let mut expected = vec![vec![]; 5];
+            let silence = || iter::repeat([0u16; 2]).take((mode.pcm_data_lines_in_field * 3) as usize);
+            reference.take_field(&mode, &mut expected[0]).unwrap();
+            encode_field(&mut reference.pcm, ControlWord::new(), silence(), &mode, &mut expected[1]);
+            encode_field(&mut reference.pcm, ControlWord::new(), silence(), &mode, &mut expected[2]);
+            reference.take_field(&mode, &mut expected[3]).unwrap();
+            reference.take_field(&mode, &mut expected[4]).unwrap();
+
+            for (index, (shown, expected)) in shown.iter().zip(expected.iter()).enumerate() {
+                assert!(*shown == expected, "field {} in {}x{}", index, mode.screen_width, mode.screen_height);
+            }
+        }
+    }
+
+    #[test]
+    fn pre_emphasis_follows_the_item_flags() {
+        let mut encoder = tone_encoder();
+        encoder.set_pre_emphasis().unwrap();
+        assert!(encoder.emphasize_item && encoder.item_control.pre_emphasis);
+
+        // (flags, filtered, flagged)
+        let items = [(None, true, true), (Some("emphasis"), false, true), (Some("no-emphasis"), false, false), (Some("copyright"), true, true)];
+        for &(flags, filtered, flagged) in items.iter() {
+            let item = PlaylistItem { file: String::from("signal:tone:1000"), ctl_flags: flags.map(String::from) };
+            let item_control = get_item_control(encoder.control, &item).unwrap();
+            encoder.start_item(item, item_control).unwrap();
+            assert_eq!(encoder.emphasize_item, filtered, "{:?}", flags);
+            assert_eq!(encoder.item_control.pre_emphasis, flagged, "{:?}", flags);
+        }
+    }
+
+    // cargo test --release -- --ignored --nocapture, on the Pi itself to mean anything
+    #[test]
+    #[ignore]
+    fn measure_silence_encoding() {
+        const FIELDS: usize = 10000;
+        let mode = get_pcm_modes()[0];
+        let mut encoder = tone_encoder();
+        let mut field = Field::new(&mode);
+
+        let start = Instant::now();
+        for _ in 0..FIELDS {
+            encoder.take_field(&mode, &mut field.lines).unwrap();
+            black_box(&mut field);
+        }
+        let plain = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..FIELDS {
+            encoder.take_field_with_silence(&mode, &mut field).unwrap();
+            black_box(&mut field);
+        }
+        let with_silence = start.elapsed();
+
+        println!("Encoding per field: {:.1} us, with the silence {:.1} us",
+            plain.as_nanos() as f64 / FIELDS as f64 / 1000.0, with_silence.as_nanos() as f64 / FIELDS as f64 / 1000.0);
+    }
+}
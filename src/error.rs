@@ -0,0 +1,41 @@
+use std::fmt;
+
+/// What stops picm, each kind exiting with its own code so scripts can tell them apart.
+/// Anything else going wrong is a bug, and panics with 101.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid command line options, config file or profile, exits with 2 like clap does
+    Usage(String),
+    /// The input can't be played: the playlist can't be read, none of its items can be
+    /// opened, or a test signal is invalid. Exits with 3.
+    Input(String),
+    /// The display can't be opened or set up for the mode, or fails while showing the fields.
+    /// Exits with 4.
+    Display(String),
+    /// An output file can't be written: the composite signal, the metrics or the field log.
+    /// Exits with 5.
+    Output(String)
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 2,
+            Error::Input(_) => 3,
+            Error::Display(_) => 4,
+            Error::Output(_) => 5
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(message) | Error::Input(message) | Error::Display(message) | Error::Output(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
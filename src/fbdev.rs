@@ -0,0 +1,329 @@
+use crate::{PCMMode, PCM_DATA_WIDTH};
+use crate::backend::{Backend, FieldScaler};
+use crate::geometry::Geometry;
+use crate::display::{DisplayResolution, Image, ImageType, RGB8};
+use crate::vsync::Vblank;
+use crate::error::{Error, Result};
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::{ptr, slice};
+
+const FBIOGET_VSCREENINFO: libc::c_ulong = 0x4600;
+const FBIOPUT_VSCREENINFO: libc::c_ulong = 0x4601;
+const FBIOGET_FSCREENINFO: libc::c_ulong = 0x4602;
+const FBIOPAN_DISPLAY: libc::c_ulong = 0x4606;
+const FBIO_WAITFORVSYNC: libc::c_ulong = 0x40044620;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FbBitfield {
+    offset: u32,
+    length: u32,
+    msb_right: u32
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FbVarScreeninfo {
+    xres: u32,
+    yres: u32,
+    xres_virtual: u32,
+    yres_virtual: u32,
+    xoffset: u32,
+    yoffset: u32,
+    bits_per_pixel: u32,
+    grayscale: u32,
+    red: FbBitfield,
+    green: FbBitfield,
+    blue: FbBitfield,
+    transp: FbBitfield,
+    nonstd: u32,
+    activate: u32,
+    height: u32,
+    width: u32,
+    accel_flags: u32,
+    pixclock: u32,
+    left_margin: u32,
+    right_margin: u32,
+    upper_margin: u32,
+    lower_margin: u32,
+    hsync_len: u32,
+    vsync_len: u32,
+    sync: u32,
+    vmode: u32,
+    rotate: u32,
+    colorspace: u32,
+    reserved: [u32; 4]
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FbFixScreeninfo {
+    id: [u8; 16],
+    smem_start: libc::c_ulong,
+    smem_len: u32,
+    fb_type: u32,
+    type_aux: u32,
+    visual: u32,
+    xpanstep: u16,
+    ypanstep: u16,
+    ywrapstep: u16,
+    line_length: u32,
+    mmio_start: libc::c_ulong,
+    mmio_len: u32,
+    accel: u32,
+    capabilities: u16,
+    reserved: [u16; 2]
+}
+
+fn color_to_pixel(color: RGB8, var: &FbVarScreeninfo) -> u32 {
+    let component = |value: u8, field: &FbBitfield| ((value as u32) >> (8 - field.length.min(8))) << field.offset;
+    component(color.r, &var.red) | component(color.g, &var.green) | component(color.b, &var.blue)
+}
+
+// RGB565, which is what the Pi console framebuffer uses
+fn default_screeninfo(resolution: DisplayResolution) -> FbVarScreeninfo {
+    FbVarScreeninfo {
+        xres: resolution.width as u32,
+        yres: resolution.height as u32,
+        xres_virtual: resolution.width as u32,
+        yres_virtual: resolution.height as u32,
+        bits_per_pixel: 16,
+        red: FbBitfield { offset: 11, length: 5, msb_right: 0 },
+        green: FbBitfield { offset: 5, length: 6, msb_right: 0 },
+        blue: FbBitfield { offset: 0, length: 5, msb_right: 0 },
+        ..Default::default()
+    }
+}
+
+/// Writes the fields into a Linux framebuffer device. A plain file can stand in for the
+/// device, in that case it's sized for the mode and there is no panning nor vsync.
+/// Without vsync support in the driver, the draw thread falls back to a software clock.
+pub struct FbDevBackend {
+    file: File,
+    device_info: Option<(FbVarScreeninfo, FbFixScreeninfo)>,
+    var: FbVarScreeninfo,
+    line_length: usize,
+    memory: *mut u8,
+    memory_len: usize,
+    pages: u32,
+    next_page: u32,
+    bytes_per_pixel: usize,
+    scaler: Option<FieldScaler>,
+    image: Image,
+    hardware_vsync: bool
+}
+
+// The mapping is only ever touched from the draw thread
+unsafe impl Send for FbDevBackend {}
+
+impl FbDevBackend {
+    pub fn open(path: &String) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)
+            .map_err(|e| Error::Display(format!("Cannot open framebuffer device {}: {}", path, e)))?;
+
+        let mut var = FbVarScreeninfo::default();
+        let mut fix = FbFixScreeninfo::default();
+        let device_info = unsafe {
+            if libc::ioctl(file.as_raw_fd(), FBIOGET_VSCREENINFO, &mut var) == 0 && libc::ioctl(file.as_raw_fd(), FBIOGET_FSCREENINFO, &mut fix) == 0 {
+                Some((var, fix))
+            } else {
+                println!("{} is not a framebuffer device, writing to it as a plain file", path);
+                None
+            }
+        };
+
+        Ok(FbDevBackend {
+            file: file,
+            device_info: device_info,
+            var: var,
+            line_length: 0,
+            memory: ptr::null_mut(),
+            memory_len: 0,
+            pages: 1,
+            next_page: 0,
+            bytes_per_pixel: 0,
+            scaler: None,
+            image: Image::new(ImageType::_8BPP, PCM_DATA_WIDTH, 1),
+            hardware_vsync: false
+        })
+    }
+
+    fn setup_pages(&mut self, mode: &PCMMode) -> Result<()> {
+        let fd = self.file.as_raw_fd();
+
+        match self.device_info {
+            Some((var, fix)) => {
+                self.var = var;
+                self.line_length = fix.line_length as usize;
+
+                // Ask for a virtual screen twice the height to flip between two pages
+                let mut double_var = var;
+                double_var.yres_virtual = var.yres * 2;
+                double_var.yoffset = 0;
+                let flipping = fix.ypanstep > 0 && unsafe { libc::ioctl(fd, FBIOPUT_VSCREENINFO, &mut double_var) } == 0 && double_var.yres_virtual >= var.yres * 2;
+                if flipping {
+                    self.var = double_var;
+                    self.pages = 2;
+                }
+
+                let mut vsync_arg = 0u32;
+                self.hardware_vsync = unsafe { libc::ioctl(fd, FBIO_WAITFORVSYNC, &mut vsync_arg) } == 0;
+            },
+            None => {
+                self.var = default_screeninfo(DisplayResolution { width: mode.screen_width, height: mode.screen_height });
+                self.line_length = (self.var.xres * self.var.bits_per_pixel / 8) as usize;
+            }
+        }
+
+        self.bytes_per_pixel = self.var.bits_per_pixel.div_ceil(8) as usize;
+        self.memory_len = self.line_length * self.var.yres as usize * self.pages as usize;
+        if self.device_info.is_none() {
+            self.file.set_len(self.memory_len as u64).map_err(|e| Error::Display(format!("Cannot resize framebuffer file: {}", e)))?;
+        }
+
+        let memory = unsafe { libc::mmap(ptr::null_mut(), self.memory_len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0) };
+        if memory == libc::MAP_FAILED {
+            return Err(Error::Display(format!("Cannot map framebuffer memory: {}", std::io::Error::last_os_error())));
+        }
+        self.memory = memory as *mut u8;
+
+        println!("Framebuffer: {}x{} {} bpp, {} page(s), {} vsync", self.var.xres, self.var.yres, self.var.bits_per_pixel, self.pages,
+            if self.hardware_vsync { "hardware" } else { "software" });
+        Ok(())
+    }
+
+    fn pan_to(&mut self, page: u32) -> Result<()> {
+        let mut var = self.var;
+        var.yoffset = page * var.yres;
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), FBIOPAN_DISPLAY, &mut var) } != 0 {
+            return Err(Error::Display(format!("Cannot pan the framebuffer: {}", std::io::Error::last_os_error())));
+        }
+        Ok(())
+    }
+
+    fn get_page(&mut self, page: u32) -> &mut [u8] {
+        let page_len = self.line_length * self.var.yres as usize;
+        unsafe { slice::from_raw_parts_mut(self.memory.add(page_len * page as usize), page_len) }
+    }
+}
+
+impl Backend for FbDevBackend {
+    fn get_resolution(&self) -> Option<DisplayResolution> {
+        self.device_info.map(|(var, _)| DisplayResolution { width: var.xres as i32, height: var.yres as i32 })
+    }
+
+    fn start(&mut self, mode: &PCMMode, geometry: &Geometry, colors: &[RGB8]) -> Result<()> {
+        self.setup_pages(mode)?;
+
+        let pixel_colors = colors.iter().map(|c| color_to_pixel(*c, &self.var).to_le_bytes()).collect();
+        let scaler = FieldScaler::new(mode, geometry, self.var.xres as i32, self.var.yres as i32, self.bytes_per_pixel, pixel_colors);
+        for page in 0..self.pages {
+            scaler.clear(self.get_page(page));
+        }
+        self.scaler = Some(scaler);
+
+        self.image = Image::new(ImageType::_8BPP, PCM_DATA_WIDTH, mode.visible_pcm_field_height);
+
+        self.next_page = if self.pages > 1 { 1 } else { 0 };
+        Ok(())
+    }
+
+    fn has_vsync(&self) -> bool {
+        self.hardware_vsync
+    }
+
+    fn wait_for_vsync(&mut self) -> Result<Option<Vblank>> {
+        let mut vsync_arg = 0u32;
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), FBIO_WAITFORVSYNC, &mut vsync_arg) } != 0 {
+            return Err(Error::Display(format!("Cannot wait for the framebuffer vsync: {}", std::io::Error::last_os_error())));
+        }
+        Ok(None)
+    }
+
+    fn back_buffer(&mut self) -> &mut Image {
+        &mut self.image
+    }
+
+    fn present(&mut self) -> Result<()> {
+        let page = self.next_page;
+        let page_len = self.line_length * self.var.yres as usize;
+        let page_memory = unsafe { slice::from_raw_parts_mut(self.memory.add(page_len * page as usize), page_len) };
+        self.scaler.as_ref().unwrap().draw(&self.image, page_memory, self.line_length);
+
+        if self.pages > 1 {
+            self.pan_to(page)?;
+            self.next_page = (page + 1) % self.pages;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FbDevBackend {
+    fn drop(&mut self) {
+        if !self.memory.is_null() {
+            unsafe { libc::munmap(self.memory as *mut libc::c_void, self.memory_len); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_pcm_modes;
+
+    use std::{env, fs, process};
+
+    const GRAY: u16 = 19 << 11 | 38 << 5 | 19;
+
+    #[test]
+    fn plain_file_gets_the_field() {
+        let mode = get_pcm_modes()[0];
+        let geometry = Geometry { left_offset: 14, top_offset: 1, full_width: 137, preamble: vec![1, 0, 1, 0], white_reference: vec![0, 2, 2, 2, 2] };
+        let colors = [RGB8 { r: 0, g: 0, b: 0 }, RGB8 { r: 153, g: 153, b: 153 }, RGB8 { r: 255, g: 255, b: 255 }];
+
+        let path = env::temp_dir().join(format!("picm-fbdev-test-{}", process::id()));
+        File::create(&path).unwrap();
+        let mut backend = FbDevBackend::open(&path.to_string_lossy().into_owned()).unwrap();
+        assert!(backend.get_resolution().is_none());
+        backend.start(&mode, &geometry, &colors).unwrap();
+
+        // A white first half in the first line, gray data in the second, the rest black
+        let image = backend.back_buffer();
+        for x in 0..PCM_DATA_WIDTH / 2 {
+            image.set_pixel_bytes(x, 0, &[2]);
+        }
+        for x in 0..PCM_DATA_WIDTH {
+            image.set_pixel_bytes(x, 1, &[1]);
+        }
+        backend.present().unwrap();
+
+        let memory = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // RGB565 at the width of the mode, a single page
+        let stride = mode.screen_width as usize * 2;
+        assert_eq!(memory.len(), stride * mode.screen_height as usize);
+        let pixel = |x: usize, y: usize| u16::from_le_bytes([memory[y * stride + x * 2], memory[y * stride + x * 2 + 1]]);
+        // First column of a data cell
+        let data_x = |n: usize| 14 + ((4 + n) as f32 * geometry.physical_pixel_width(&mode)).ceil() as usize;
+        assert_eq!(data_x(0), 35);
+
+        // Every line is doubled, from the top offset down
+        for y in 1..3 {
+            assert_eq!(pixel(14, y), GRAY, "preamble in row {}", y);
+            assert_eq!(pixel(data_x(0), y), 0xffff, "first data cell in row {}", y);
+            assert_eq!(pixel(data_x(63), y), 0xffff, "last white data cell in row {}", y);
+            assert_eq!(pixel(data_x(64), y), 0, "second half in row {}", y);
+            assert_eq!(pixel(mode.screen_width as usize - 1, y), 0xffff, "white reference in row {}", y);
+        }
+        for y in 3..5 {
+            assert_eq!(pixel(data_x(0), y), GRAY, "second line in row {}", y);
+        }
+        assert!((0..mode.screen_width as usize).all(|x| pixel(x, 0) == 0));
+        assert!((0..14).all(|x| pixel(x, 1) == 0));
+        assert_eq!(pixel(data_x(0), 5), 0);
+        assert_eq!(pixel(14, 5), GRAY);
+    }
+}
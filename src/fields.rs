@@ -0,0 +1,223 @@
+use crate::PCMMode;
+use crate::pcm::{self, ControlWord};
+use crate::report::{Event, Reporter};
+
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError};
+
+/// A field of lines to show, the CTL line first, along with what the draw thread needs to
+/// fill in when the field after it is late. That is only encoded for fields going into a
+/// queue running low, and left empty otherwise.
+pub struct Field {
+    /// Position in the order the source encoded its fields
+    pub sequence: u64,
+    pub lines: Vec<u128>,
+    /// The same field as it goes on after silence, the interleave carries zeros into its
+    /// first lines instead of the words of the field before
+    pub after_silence: Vec<u128>,
+    /// Digital silence carrying on from the field, through the same interleave
+    pub silence: Vec<u128>
+}
+
+impl Field {
+    pub fn new(mode: &PCMMode) -> Self {
+        let lines = mode.visible_pcm_field_height as usize;
+        Field {
+            sequence: 0,
+            lines: Vec::with_capacity(lines),
+            after_silence: Vec::with_capacity(lines),
+            silence: Vec::with_capacity(lines)
+        }
+    }
+
+    /// Leaves out what is filled in when the field after it is late
+    pub fn without_silence(&mut self) {
+        self.after_silence.clear();
+        self.silence.clear();
+    }
+}
+
+/// How far the producer is ahead of the draw thread, shared between the two
+#[derive(Clone)]
+pub struct QueueDepth {
+    received: Arc<AtomicU64>
+}
+
+impl QueueDepth {
+    pub fn new() -> Self {
+        QueueDepth { received: Arc::new(AtomicU64::new(0)) }
+    }
+
+    /// Fields queued ahead of the given one
+    pub fn ahead_of(&self, sequence: u64) -> u64 {
+        sequence.saturating_sub(self.received.load(Ordering::Relaxed))
+    }
+}
+
+/// Silence after silence: once a field of it went through the interleave, every line is the same
+pub fn fill_silence(ctl_line: u128, mode: &PCMMode, lines: &mut Vec<u128>) {
+    lines.clear();
+    lines.push(ctl_line);
+    lines.resize(mode.visible_pcm_field_height as usize, pcm::add_crc_to_data(0));
+}
+
+/// Hands the encoded fields to the draw thread in order, whole fields at a time. Never waits
+/// for the audio: when the next field is late, the silence encoded along with the last one
+/// is shown in its place, so the interleave goes on unbroken. Everything shown is encoded
+/// ahead by the producer, and the fields go back to it to be filled again, so nothing is
+/// encoded, locked or allocated on the draw thread.
+///
+/// The producer leaves the silence out of fields going into a deep queue. Should it stall
+/// for that long anyway, plain silence is shown instead, and the words of the last field
+/// still in the interleave are lost. A field without the lines after silence is shown as
+/// it is, its first lines then carry those words after the silence.
+pub struct FieldQueue {
+    receiver: Receiver<Field>,
+    spent: SyncSender<Field>,
+    current: Field,
+    /// Shown once the silence of the current field went through
+    flat_silence: Vec<u128>,
+    /// Silence is on screen, the next field goes on after it
+    silenced: bool,
+    next_sequence: u64,
+    playing: bool,
+    underruns: u64,
+    /// Of the underrun going on
+    underrun_fields: u64,
+    silent_fields: u64,
+    depth: QueueDepth,
+    reporter: Reporter
+}
+
+impl FieldQueue {
+    pub fn new(receiver: Receiver<Field>, spent: SyncSender<Field>, depth: QueueDepth, mode: PCMMode, reporter: Reporter) -> Self {
+        let mut flat_silence = Vec::with_capacity(mode.visible_pcm_field_height as usize);
+        fill_silence(ControlWord::new().to_line(), &mode, &mut flat_silence);
+
+        FieldQueue {
+            receiver: receiver,
+            spent: spent,
+            current: Field::new(&mode),
+            flat_silence: flat_silence,
+            // Nothing went through the interleave yet
+            silenced: true,
+            next_sequence: 0,
+            playing: false,
+            underruns: 0,
+            underrun_fields: 0,
+            silent_fields: 0,
+            depth: depth,
+            reporter: reporter
+        }
+    }
+
+    /// The lines to show next and their position in the order of the source, None for
+    /// silence filled in. None when the encoder stopped and every field it sent was shown.
+    pub fn next_field(&mut self) -> Option<(Option<u64>, &Vec<u128>)> {
+        let field = match self.receiver.try_recv() {
+            Ok(field) => field,
+            Err(TryRecvError::Empty) => {
+                self.count_silence();
+                if self.silenced {
+                    return Some((None, &self.flat_silence));
+                }
+                self.silenced = true;
+                self.flat_silence[0] = self.current.lines[0];
+                if self.current.silence.is_empty() {
+                    return Some((None, &self.flat_silence));
+                }
+                return Some((None, &self.current.silence));
+            },
+            Err(TryRecvError::Disconnected) => return None
+        };
+
+        let silenced = self.silenced;
+        self.receive(field);
+        let lines = if silenced && !self.current.after_silence.is_empty() { &self.current.after_silence } else { &self.current.lines };
+        Some((Some(self.current.sequence), lines))
+    }
+
+    /// Drops the next field for a vsync missed while the previous one stayed on screen, when
+    /// there is one queued. Nothing is filled in when there isn't, the clock has moved on.
+    pub fn skip_field(&mut self) {
+        if let Ok(field) = self.receiver.try_recv() {
+            self.receive(field);
+        }
+    }
+
+    fn receive(&mut self, field: Field) {
+        if field.sequence != self.next_sequence {
+            panic!("Field {} arrived instead of {}", field.sequence, self.next_sequence);
+        }
+        self.next_sequence += 1;
+        self.depth.received.store(self.next_sequence, Ordering::Relaxed);
+
+        if self.underrun_fields > 0 {
+            self.reporter.send(Event::Underrun { silent_fields: self.underrun_fields, underruns: self.underruns });
+            self.underrun_fields = 0;
+        }
+        self.playing = true;
+        self.silenced = false;
+
+        let shown = mem::replace(&mut self.current, field);
+        // Dropped on the floor when the encoder has enough already
+        let _ = self.spent.try_send(shown);
+    }
+
+    /// Fields received from the source, silence not included
+    pub fn fields_received(&self) -> u64 {
+        self.next_sequence
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underruns
+    }
+
+    /// Fields of silence filled in, of every underrun
+    pub fn silent_fields(&self) -> u64 {
+        self.silent_fields
+    }
+
+    fn count_silence(&mut self) {
+        // Before the first field it is just the encoder starting up
+        if self.playing {
+            if self.underrun_fields == 0 { self.underruns += 1; }
+            self.underrun_fields += 1;
+            self.silent_fields += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_pcm_modes, report};
+    use std::sync::{mpsc, Mutex};
+
+    #[test]
+    fn fields_without_silence_are_filled_in_plainly() {
+        let mode = get_pcm_modes()[0];
+        let (reporter, _) = report::spawn(&mode, None, None, Arc::new(Mutex::new(Default::default())));
+        let (sender, receiver) = mpsc::sync_channel(2);
+        let (spent, _spent_receiver) = mpsc::sync_channel(2);
+        let depth = QueueDepth::new();
+        let mut queue = FieldQueue::new(receiver, spent, depth.clone(), mode, reporter);
+
+        let mut field = Field::new(&mode);
+        field.lines = (0..mode.visible_pcm_field_height as u128).map(|line| line + 7).collect();
+        sender.send(field).unwrap();
+        assert_eq!(depth.ahead_of(1), 1);
+
+        // Straight after the silence of the start, the field as it is
+        let (sequence, lines) = queue.next_field().unwrap();
+        assert_eq!((sequence, lines[1]), (Some(0), 8));
+        assert_eq!(depth.ahead_of(1), 0);
+
+        let (sequence, lines) = queue.next_field().unwrap();
+        assert_eq!((sequence, lines[0]), (None, 7));
+        assert!(lines[1..].iter().all(|line| *line == pcm::add_crc_to_data(0)));
+        assert_eq!(queue.underruns(), 1);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::{PCMMode, PCM_DATA_WIDTH};
+use crate::bits_to_pixels;
+use crate::pcm;
+
+/// Where the PCM lines are put on screen and what surrounds the data bits. Every line is
+/// the preamble, the 128 data bits, black padding up to the full width, then the white
+/// reference, stretched over the screen width right of the left offset.
+#[derive(Clone)]
+pub struct Geometry {
+    /// Blank pixels left of the PCM line
+    pub left_offset: i32,
+    /// Blank framebuffer rows above the first PCM line
+    pub top_offset: i32,
+    /// Bit cells in a PCM line
+    pub full_width: i32,
+    /// Palette indices of the bit cells before the data
+    pub preamble: Vec<u8>,
+    /// Palette indices of the bit cells ending the line
+    pub white_reference: Vec<u8>
+}
+
+/// Parses a cell pattern like 1010, every digit is a palette index (0 black, 1 gray, 2 white)
+pub fn parse_pattern(pattern: &str) -> Option<Vec<u8>> {
+    pattern.chars().map(|c| c.to_digit(10).filter(|d| *d <= 2).map(|d| d as u8)).collect()
+}
+
+impl Geometry {
+    pub fn preamble_width(&self) -> i32 {
+        self.preamble.len() as i32
+    }
+
+    pub fn white_reference_start(&self) -> i32 {
+        self.full_width - self.white_reference.len() as i32
+    }
+
+    /// Screen pixels per bit cell
+    pub fn physical_pixel_width(&self, mode: &PCMMode) -> f32 {
+        (mode.screen_width - self.left_offset) as f32 / self.full_width as f32
+    }
+
+    /// Line with everything but the data bits, which are left black
+    pub fn get_base_line(&self) -> Vec<u8> {
+        let mut line = vec![0u8; self.full_width as usize];
+        line[..self.preamble.len()].copy_from_slice(&self.preamble);
+        line[self.white_reference_start() as usize..].copy_from_slice(&self.white_reference);
+        line
+    }
+
+    pub fn get_line_pixel_bytes(&self, line_data: u128) -> Vec<u8> {
+        let mut line = self.get_base_line();
+        bits_to_pixels(line_data, &mut line[self.preamble.len()..]);
+        line
+    }
+
+    /// Checks the geometry and the line counts of the mode fit together and on the screen
+    pub fn validate(&self, mode: &PCMMode) -> Result<(), String> {
+        if mode.screen_width <= 0 || mode.screen_height <= 0 || mode.field_rate <= 0 || mode.pcm_data_lines_in_field <= 0 {
+            return Err(format!("mode {}x{} with {} lines at {} fields per second is not possible", mode.screen_width, mode.screen_height, mode.pcm_data_lines_in_field, mode.field_rate));
+        }
+        // Silence filled in for a late field carries the interleave on within a field
+        if mode.pcm_data_lines_in_field < pcm::INTERLEAVE_SPAN as i32 {
+            return Err(format!("{} lines per field are fewer than the {} lines the interleave spans", mode.pcm_data_lines_in_field, pcm::INTERLEAVE_SPAN));
+        }
+        if self.left_offset < 0 || self.top_offset < 0 {
+            return Err(format!("offsets can't be negative (left {}, top {})", self.left_offset, self.top_offset));
+        }
+        if self.preamble.is_empty() || self.white_reference.is_empty() {
+            return Err(String::from("preamble and white reference need at least one cell"));
+        }
+
+        let minimum_width = self.preamble_width() + PCM_DATA_WIDTH + self.white_reference.len() as i32;
+        if self.full_width < minimum_width {
+            return Err(format!("line width {} can't fit the preamble, {} data bits and the white reference, needs at least {}", self.full_width, PCM_DATA_WIDTH, minimum_width));
+        }
+        if self.physical_pixel_width(mode) < 1.0 {
+            return Err(format!("{} bit cells don't fit in the {} pixels right of the left offset", self.full_width, mode.screen_width - self.left_offset));
+        }
+
+        if mode.visible_pcm_field_height < 2 {
+            return Err(format!("{} visible lines leave no room for data after the control line", mode.visible_pcm_field_height));
+        }
+        // Lines are doubled, the last one needs at least its first row on screen
+        if self.top_offset + (mode.visible_pcm_field_height - 1) * 2 >= mode.screen_height {
+            return Err(format!("{} visible lines from row {} don't fit in {} rows", mode.visible_pcm_field_height, self.top_offset, mode.screen_height));
+        }
+        if mode.visible_pcm_data_field_height > mode.pcm_data_lines_in_field {
+            return Err(format!("{} visible data lines are more than the {} lines in a field", mode.visible_pcm_data_field_height, mode.pcm_data_lines_in_field));
+        }
+
+        Ok(())
+    }
+
+    pub fn print(&self, mode: &PCMMode) {
+        println!("Picture: left offset {}, top offset {}, {} cells per line ({:.3} pixels each), {} data lines per field, {} of them visible",
+            self.left_offset, self.top_offset, self.full_width, self.physical_pixel_width(mode),
+            mode.pcm_data_lines_in_field, mode.visible_pcm_data_field_height);
+
+        // Every line carries three stereo samples
+        let sample_rate = mode.pcm_data_lines_in_field * 3 * mode.field_rate;
+        if sample_rate != 44100 {
+            println!("Warning: {} lines per field at {} Hz play the audio at {} Hz", mode.pcm_data_lines_in_field, mode.field_rate, sample_rate);
+        }
+    }
+}
@@ -0,0 +1,453 @@
+use crate::{PCMMode, PCM_DATA_WIDTH};
+use crate::get_pcm_modes;
+use crate::backend::{Backend, FieldScaler};
+use crate::geometry::Geometry;
+use crate::display::{DisplayResolution, Image, ImageType, RGB8};
+use crate::vsync::Vblank;
+use crate::error::{Error, Result};
+
+use drm::{Device as BasicDevice, VblankWaitFlags, VblankWaitTarget};
+use drm::control::{self, atomic, connector, crtc, framebuffer, plane, property, AtomicCommitFlags, Device as ControlDevice};
+use drm::control::dumbbuffer::DumbBuffer;
+use drm::buffer::{Buffer, DrmFourcc};
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsFd, BorrowedFd};
+use std::slice;
+use std::time::Duration;
+
+struct Card(File);
+
+impl AsFd for Card {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl BasicDevice for Card {}
+impl ControlDevice for Card {}
+
+struct ScanoutBuffer {
+    buffer: DumbBuffer,
+    framebuffer: framebuffer::Handle,
+    memory: *mut u8,
+    memory_len: usize,
+    flip: FlipRequest
+}
+
+// The atomic commit showing a buffer on the plane, built once so flipping doesn't allocate:
+// drm's AtomicModeReq is taken by value on every commit
+struct FlipRequest {
+    plane: u32,
+    props: Vec<u32>,
+    values: Vec<u64>
+}
+
+impl FlipRequest {
+    fn commit(&mut self, card: &Card, flags: AtomicCommitFlags) -> io::Result<()> {
+        let mut prop_count = [self.props.len() as u32];
+        drm_ffi::mode::atomic_commit(card.as_fd(), flags.bits(), slice::from_mut(&mut self.plane), &mut prop_count, &mut self.props, &mut self.values)
+    }
+}
+
+// Setting up the display failed, telling what was being done
+fn display_error(what: &'static str) -> impl Fn(io::Error) -> Error {
+    move |e| Error::Display(format!("{}: {}", what, e))
+}
+
+fn get_property_handles<T: control::ResourceHandle>(card: &Card, handle: T) -> Result<HashMap<String, property::Handle>> {
+    Ok(card.get_properties(handle).map_err(display_error("Cannot get DRM object properties"))?
+        .as_hashmap(card).map_err(display_error("Cannot get DRM property info"))?
+        .into_iter().map(|(name, info)| (name, info.handle())).collect())
+}
+
+fn is_pcm_mode(mode: &control::Mode, pcm_modes: &[PCMMode]) -> bool {
+    let (width, height) = mode.size();
+    mode.flags().contains(control::ModeFlags::INTERLACE) &&
+        pcm_modes.iter().any(|m| m.screen_width == width as i32 && m.screen_height == height as i32 && m.field_rate == mode.vrefresh() as i32)
+}
+
+// The PCM mode of a connector, the preferred one when it offers more
+fn pick_mode(modes: &[control::Mode], pcm_modes: &[PCMMode]) -> Option<control::Mode> {
+    let mut candidates = modes.iter().filter(|m| is_pcm_mode(m, pcm_modes));
+    candidates.clone().find(|m| m.mode_type().contains(control::ModeTypeFlags::PREFERRED)).or_else(|| candidates.next()).copied()
+}
+
+// What a vsync wait blocks on
+#[derive(Debug, PartialEq)]
+enum VsyncWait {
+    /// The event of the flip committed last, which comes at the vblank showing it
+    PageFlip,
+    /// Nothing was presented since the last vblank, there is no flip to wait for
+    Vblank
+}
+
+// Page flip bookkeeping, apart from the device. The driver refuses a commit while a flip
+// is pending, so there is at most one. Also keeps the 32 bit vblank counter of the driver
+// counting on past its wrap.
+#[derive(Default)]
+struct FlipState {
+    pending: bool,
+    last_frame: Option<u32>,
+    sequence: u64
+}
+
+impl FlipState {
+    fn vsync_wait(&self) -> VsyncWait {
+        if self.pending { VsyncWait::PageFlip } else { VsyncWait::Vblank }
+    }
+
+    // Events need to be read before the next commit
+    fn can_commit(&self) -> bool {
+        !self.pending
+    }
+
+    fn committed(&mut self) {
+        assert!(!self.pending, "DRM commit while a page flip is pending");
+        self.pending = true;
+    }
+
+    fn page_flipped(&mut self) {
+        self.pending = false;
+    }
+
+    // The vblank the driver counted as the frame, at the time it stamped it on the
+    // monotonic clock
+    fn vblank(&mut self, frame: u32, time: Duration) -> Vblank {
+        if let Some(last_frame) = self.last_frame {
+            self.sequence += frame.wrapping_sub(last_frame) as u64;
+        }
+        self.last_frame = Some(frame);
+        Vblank { sequence: self.sequence, ns: time.as_nanos() }
+    }
+}
+
+/// Shows the fields through DRM/KMS on the composite connector, flipping between two
+/// dumb buffers with atomic commits at vblank.
+pub struct KmsBackend {
+    card: Card,
+    connector: connector::Handle,
+    crtc: crtc::Handle,
+    // Position of the CRTC in the resources, vblank waits address it by that
+    crtc_index: u32,
+    plane: plane::Handle,
+    // Looked up once, not on every flip
+    plane_properties: HashMap<String, property::Handle>,
+    mode: control::Mode,
+    buffers: Vec<ScanoutBuffer>,
+    next_buffer: usize,
+    flips: FlipState,
+    scaler: Option<FieldScaler>,
+    image: Image
+}
+
+// The mappings are only ever touched from the draw thread
+unsafe impl Send for KmsBackend {}
+
+impl KmsBackend {
+    /// Sets the requested mode on the connector, or a PCM mode it offers (the preferred one
+    /// when there are more) when None
+    pub fn open(path: &String, requested: Option<PCMMode>) -> Result<Self> {
+        let card = Card(OpenOptions::new().read(true).write(true).open(path)
+            .map_err(|e| Error::Display(format!("Cannot open DRM device {}: {}", path, e)))?);
+
+        card.set_client_capability(drm::ClientCapability::UniversalPlanes, true).map_err(display_error("DRM device has no universal planes support"))?;
+        card.set_client_capability(drm::ClientCapability::Atomic, true).map_err(display_error("DRM device has no atomic modesetting support"))?;
+
+        let resources = card.resource_handles().map_err(display_error("Cannot get DRM resources"))?;
+        let connectors: Vec<connector::Info> = resources.connectors().iter().flat_map(|c| card.get_connector(*c, true)).collect();
+
+        let pcm_modes = match requested {
+            Some(mode) => vec![mode],
+            None => get_pcm_modes()
+        };
+
+        // Prefer the composite output, but take anything which can show a PCM mode (vkms for example)
+        let connector = connectors.iter()
+            .filter(|c| c.state() != connector::State::Disconnected && pick_mode(c.modes(), &pcm_modes).is_some())
+            .min_by_key(|c| if c.interface() == connector::Interface::Composite { 0 } else { 1 })
+            .ok_or_else(|| Error::Display(match requested {
+                Some(mode) => format!("No connected DRM connector with a {}x{} interlaced mode at {} fields per second", mode.screen_width, mode.screen_height, mode.field_rate),
+                None => String::from("No connected DRM connector with a 576i or 480i mode")
+            }))?;
+
+        let mode = pick_mode(connector.modes(), &pcm_modes).unwrap();
+
+        let crtc = connector.encoders().iter()
+            .flat_map(|e| card.get_encoder(*e))
+            .flat_map(|e| resources.filter_crtcs(e.possible_crtcs()))
+            .next()
+            .ok_or_else(|| Error::Display(String::from("No CRTC for the DRM connector")))?;
+        let crtc_index = resources.crtcs().iter().position(|c| *c == crtc).unwrap() as u32;
+
+        let plane = card.plane_handles().map_err(display_error("Cannot get DRM planes"))?.into_iter()
+            .filter(|p| card.get_plane(*p).map(|info| resources.filter_crtcs(info.possible_crtcs()).contains(&crtc)).unwrap_or(false))
+            .find(|p| {
+                // Planes whose properties can't be read are passed over
+                let properties = match card.get_properties(*p) {
+                    Ok(properties) => properties,
+                    Err(_) => return false
+                };
+                let (ids, values) = properties.as_props_and_values();
+                ids.iter().zip(values.iter()).any(|(id, value)| {
+                    card.get_property(*id).map(|info| info.name().to_str() == Ok("type")).unwrap_or(false) && *value == control::PlaneType::Primary as u64
+                })
+            })
+            .ok_or_else(|| Error::Display(String::from("No primary plane for the DRM CRTC")))?;
+
+        println!("DRM: {} connector, mode {:?} {}i", connector.interface().as_str(), mode.size(), mode.vrefresh());
+        let plane_properties = get_property_handles(&card, plane)?;
+
+        Ok(KmsBackend {
+            card: card,
+            connector: connector.handle(),
+            crtc: crtc,
+            crtc_index: crtc_index,
+            plane: plane,
+            plane_properties: plane_properties,
+            mode: mode,
+            buffers: vec![],
+            next_buffer: 0,
+            flips: FlipState::default(),
+            scaler: None,
+            image: Image::new(ImageType::_8BPP, PCM_DATA_WIDTH, 1)
+        })
+    }
+
+    fn create_buffer(&self) -> Result<ScanoutBuffer> {
+        let (width, height) = self.mode.size();
+        let mut buffer = self.card.create_dumb_buffer((width as u32, height as u32), DrmFourcc::Xrgb8888, 32).map_err(display_error("Cannot create DRM dumb buffer"))?;
+        let framebuffer = self.card.add_framebuffer(&buffer, 24, 32).map_err(display_error("Cannot create DRM framebuffer"))?;
+
+        // Kept mapped for the lifetime of the backend, unmapped on drop
+        let mut mapping = self.card.map_dumb_buffer(&mut buffer).map_err(display_error("Cannot map DRM dumb buffer"))?;
+        let (memory, memory_len) = (mapping.as_mut_ptr(), mapping.len());
+        std::mem::forget(mapping);
+
+        let flip = self.flip_request(framebuffer);
+        Ok(ScanoutBuffer { buffer: buffer, framebuffer: framebuffer, memory: memory, memory_len: memory_len, flip: flip })
+    }
+
+    // The plane properties showing the framebuffer across the whole mode
+    fn plane_values(&self, framebuffer: framebuffer::Handle) -> [(property::Handle, property::Value<'static>); 10] {
+        let plane_properties = &self.plane_properties;
+        let (width, height) = self.mode.size();
+        [
+            (plane_properties["FB_ID"], property::Value::Framebuffer(Some(framebuffer))),
+            (plane_properties["CRTC_ID"], property::Value::CRTC(Some(self.crtc))),
+            (plane_properties["SRC_X"], property::Value::UnsignedRange(0)),
+            (plane_properties["SRC_Y"], property::Value::UnsignedRange(0)),
+            (plane_properties["SRC_W"], property::Value::UnsignedRange((width as u64) << 16)),
+            (plane_properties["SRC_H"], property::Value::UnsignedRange((height as u64) << 16)),
+            (plane_properties["CRTC_X"], property::Value::SignedRange(0)),
+            (plane_properties["CRTC_Y"], property::Value::SignedRange(0)),
+            (plane_properties["CRTC_W"], property::Value::UnsignedRange(width as u64)),
+            (plane_properties["CRTC_H"], property::Value::UnsignedRange(height as u64))
+        ]
+    }
+
+    fn plane_request(&self, framebuffer: framebuffer::Handle) -> atomic::AtomicModeReq {
+        let mut request = atomic::AtomicModeReq::new();
+        for (handle, value) in self.plane_values(framebuffer).iter() {
+            request.add_property(self.plane, *handle, *value);
+        }
+        request
+    }
+
+    fn flip_request(&self, framebuffer: framebuffer::Handle) -> FlipRequest {
+        let values = self.plane_values(framebuffer);
+        FlipRequest {
+            plane: self.plane.into(),
+            props: values.iter().map(|(handle, _)| (*handle).into()).collect(),
+            values: values.iter().map(|(_, value)| (*value).into()).collect()
+        }
+    }
+
+    // Blocks until there are events to read
+    // The vblank of the last page flip among the events, if there was one
+    fn receive_events(&mut self) -> Result<Option<Vblank>> {
+        let mut vblank = None;
+        for event in self.card.receive_events().map_err(display_error("Cannot read DRM events"))? {
+            if let control::Event::PageFlip(flip) = event {
+                self.flips.page_flipped();
+                vblank = Some(self.flips.vblank(flip.frame, flip.duration));
+            }
+        }
+        Ok(vblank)
+    }
+
+    fn flip_to(&mut self, buffer: usize) -> Result<()> {
+        // Paced by the software clock wait_for_vsync is not called to take the events, and a
+        // commit while the last flip is still pending would be refused. The flip is done by
+        // the vblank after it was committed, at most a field to wait.
+        while !self.flips.can_commit() {
+            self.receive_events()?;
+        }
+
+        self.buffers[buffer].flip.commit(&self.card, AtomicCommitFlags::PAGE_FLIP_EVENT | AtomicCommitFlags::NONBLOCK).map_err(display_error("DRM page flip failed"))?;
+        self.flips.committed();
+        Ok(())
+    }
+}
+
+impl Backend for KmsBackend {
+    fn get_resolution(&self) -> Option<DisplayResolution> {
+        let (width, height) = self.mode.size();
+        Some(DisplayResolution { width: width as i32, height: height as i32 })
+    }
+
+    fn start(&mut self, mode: &PCMMode, geometry: &Geometry, colors: &[RGB8]) -> Result<()> {
+        for _ in 0..2 {
+            let buffer = self.create_buffer()?;
+            self.buffers.push(buffer);
+        }
+
+        // XRGB8888, little endian
+        let pixel_colors = colors.iter().map(|c| [c.b, c.g, c.r, 0]).collect();
+        let (width, height) = self.mode.size();
+        let scaler = FieldScaler::new(mode, geometry, width as i32, height as i32, 4, pixel_colors);
+        for buffer in &self.buffers {
+            scaler.clear(unsafe { slice::from_raw_parts_mut(buffer.memory, buffer.memory_len) });
+        }
+        self.scaler = Some(scaler);
+        self.image = Image::new(ImageType::_8BPP, PCM_DATA_WIDTH, mode.visible_pcm_field_height);
+
+        // Modeset showing the first buffer
+        let connector_properties = get_property_handles(&self.card, self.connector)?;
+        let crtc_properties = get_property_handles(&self.card, self.crtc)?;
+        let mode_blob = self.card.create_property_blob(&self.mode).map_err(display_error("Cannot create DRM mode blob"))?;
+
+        let mut request = self.plane_request(self.buffers[0].framebuffer);
+        request.add_property(self.connector, connector_properties["CRTC_ID"], property::Value::CRTC(Some(self.crtc)));
+        request.add_property(self.crtc, crtc_properties["MODE_ID"], mode_blob);
+        request.add_property(self.crtc, crtc_properties["ACTIVE"], property::Value::Boolean(true));
+        self.card.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, request).map_err(display_error("DRM modeset failed"))?;
+
+        // Flip to the same buffer, so the first vsync wait has an event to wait for
+        self.flip_to(0)?;
+        self.next_buffer = 1;
+        Ok(())
+    }
+
+    fn has_vsync(&self) -> bool {
+        true
+    }
+
+    fn wait_for_vsync(&mut self) -> Result<Option<Vblank>> {
+        match self.flips.vsync_wait() {
+            VsyncWait::Vblank => {
+                let reply = self.card.wait_vblank(VblankWaitTarget::Relative(1), VblankWaitFlags::empty(), self.crtc_index, 0)
+                    .map_err(display_error("Cannot wait for DRM vblank"))?;
+                Ok(reply.time().map(|time| self.flips.vblank(reply.frame(), time)))
+            },
+            VsyncWait::PageFlip => {
+                let mut vblank = None;
+                while !self.flips.can_commit() {
+                    vblank = self.receive_events()?;
+                }
+                Ok(vblank)
+            }
+        }
+    }
+
+    fn back_buffer(&mut self) -> &mut Image {
+        &mut self.image
+    }
+
+    fn present(&mut self) -> Result<()> {
+        let buffer = &self.buffers[self.next_buffer];
+        let memory = unsafe { slice::from_raw_parts_mut(buffer.memory, buffer.memory_len) };
+        self.scaler.as_ref().unwrap().draw(&self.image, memory, buffer.buffer.pitch() as usize);
+
+        let next_buffer = self.next_buffer;
+        self.flip_to(next_buffer)?;
+        self.next_buffer = if next_buffer == 1 { 0 } else { 1 };
+        Ok(())
+    }
+}
+
+impl Drop for KmsBackend {
+    fn drop(&mut self) {
+        for buffer in self.buffers.drain(..) {
+            unsafe { libc::munmap(buffer.memory as *mut libc::c_void, buffer.memory_len); }
+            let _ = self.card.destroy_framebuffer(buffer.framebuffer);
+            let _ = self.card.destroy_dumb_buffer(buffer.buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(width: u16, height: u16, refresh: u32, interlaced: bool, preferred: bool) -> control::Mode {
+        control::Mode::from(drm_ffi::drm_mode_modeinfo {
+            hdisplay: width,
+            vdisplay: height,
+            vrefresh: refresh,
+            flags: if interlaced { drm_ffi::DRM_MODE_FLAG_INTERLACE } else { 0 },
+            type_: drm_ffi::DRM_MODE_TYPE_DRIVER | if preferred { drm_ffi::DRM_MODE_TYPE_PREFERRED } else { 0 },
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn picks_an_interlaced_pcm_mode() {
+        let pal = mode(720, 576, 50, true, false);
+        let ntsc = mode(720, 480, 60, true, false);
+        let modes = [mode(720, 576, 50, false, true), mode(720, 576, 60, true, false), ntsc, pal];
+
+        // Progressive and other rates are passed over, the first one fitting is taken
+        assert!(pick_mode(&modes, &get_pcm_modes()) == Some(ntsc));
+        assert!(pick_mode(&modes, &get_pcm_modes()[..1]) == Some(pal));
+        assert!(pick_mode(&modes[..2], &get_pcm_modes()).is_none());
+        assert!(pick_mode(&[], &get_pcm_modes()).is_none());
+    }
+
+    #[test]
+    fn picks_the_preferred_mode() {
+        let preferred_pal = mode(720, 576, 50, true, true);
+        let modes = [mode(720, 480, 60, true, false), preferred_pal];
+        assert!(pick_mode(&modes, &get_pcm_modes()) == Some(preferred_pal));
+
+        // Unless it isn't the requested one
+        assert!(pick_mode(&modes, &get_pcm_modes()[1..]) == Some(modes[0]));
+    }
+
+    #[test]
+    fn vsync_waits_for_the_pending_flip() {
+        let mut flips = FlipState::default();
+        // Nothing presented yet, a plain vblank wait
+        assert_eq!(flips.vsync_wait(), VsyncWait::Vblank);
+        assert!(flips.can_commit());
+
+        for _ in 0..3 {
+            flips.committed();
+            assert_eq!(flips.vsync_wait(), VsyncWait::PageFlip);
+            assert!(!flips.can_commit());
+
+            flips.page_flipped();
+            assert_eq!(flips.vsync_wait(), VsyncWait::Vblank);
+            assert!(flips.can_commit());
+        }
+    }
+
+    #[test]
+    fn vblank_counter_goes_on_past_the_wrap() {
+        let mut flips = FlipState::default();
+        let sequences: Vec<u64> = [u32::MAX - 1, u32::MAX, 0, 3].iter()
+            .map(|frame| flips.vblank(*frame, Duration::from_millis(20)).sequence).collect();
+        assert_eq!(sequences, [0, 1, 2, 5]);
+        assert_eq!(flips.vblank(3, Duration::new(7, 40)), Vblank { sequence: 5, ns: 7_000_000_040 });
+    }
+
+    #[test]
+    #[should_panic]
+    fn second_commit_needs_the_flip_event() {
+        let mut flips = FlipState::default();
+        flips.committed();
+        flips.committed();
+    }
+}
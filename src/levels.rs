@@ -0,0 +1,64 @@
+use crate::PCM_DATA_WIDTH;
+use crate::display::{Image, RGB8};
+
+// Gray steps from black to full white in the calibration picture
+const CALIBRATION_STEPS: i32 = 8;
+
+/// Framebuffer values of the three palette entries the lines are drawn with: blanking
+/// (and data 0), data 1 and the white reference.
+#[derive(Copy, Clone)]
+pub struct Levels {
+    pub black: u8,
+    pub data: u8,
+    pub white: u8
+}
+
+fn gray(level: u8) -> RGB8 {
+    RGB8 { r: level, g: level, b: level }
+}
+
+impl Levels {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.black < self.data && self.data <= self.white {
+            Ok(())
+        } else {
+            Err(format!("black {}, data {} and white {} need to be in increasing order", self.black, self.data, self.white))
+        }
+    }
+
+    /// Palette indexed by the PCM line pixels: 0 black, 1 data, 2 white
+    pub fn get_palette(&self) -> Vec<RGB8> {
+        vec![gray(self.black), gray(self.data), gray(self.white)]
+    }
+
+    /// The line palette followed by the calibration steps
+    pub fn get_calibration_palette(&self) -> Vec<RGB8> {
+        let mut palette = self.get_palette();
+        palette.extend((0..CALIBRATION_STEPS).map(|s| gray((s * 255 / (CALIBRATION_STEPS - 1)) as u8)));
+        palette
+    }
+
+    /// Relative luma of the line palette, 0 is black and 1 is full white
+    pub fn get_lumas(&self) -> [f32; 3] {
+        [gray(self.black).luma(), gray(self.data).luma(), gray(self.white).luma()]
+    }
+
+    pub fn print(&self) {
+        let lumas = self.get_lumas();
+        println!("Levels: black {} ({:.1}%), data {} ({:.1}%), white {} ({:.1}%)",
+            self.black, lumas[0] * 100.0, self.data, lumas[1] * 100.0, self.white, lumas[2] * 100.0);
+    }
+}
+
+/// Fills the data area with stepped patches to measure the levels on a scope or a capture.
+/// The upper two thirds step from black to full white, the rest shows the black, data and
+/// white levels, then black again. Uses the calibration palette.
+pub fn draw_calibration(image: &mut Image) {
+    let step_width = PCM_DATA_WIDTH / CALIBRATION_STEPS;
+    let steps: Vec<u8> = (0..PCM_DATA_WIDTH).map(|x| 3 + (x / step_width).min(CALIBRATION_STEPS - 1) as u8).collect();
+    let patches: Vec<u8> = (0..PCM_DATA_WIDTH).map(|x| [0, 1, 2, 0][(x * 4 / PCM_DATA_WIDTH) as usize]).collect();
+
+    for h in 0..image.height {
+        image.set_pixel_bytes(0, h, if h < image.height * 2 / 3 { &steps } else { &patches });
+    }
+}
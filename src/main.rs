@@ -2,34 +2,59 @@ mod display;
 mod timer;
 mod pcm;
 mod playlist;
-
-use display::{Display, Image, Rect, ImageType, ImageResource, Palette, RGB8, VSyncData};
-use pcm::PCMEngine;
-use timer::AvgPerformanceTimer;
+mod encoder;
+mod simulator;
+mod cvbs;
+mod backend;
+mod fbdev;
+mod kms;
+mod vsync;
+mod resample;
+mod geometry;
+mod config;
+mod levels;
+mod pattern;
+mod signal;
+mod emphasis;
+mod fields;
+mod telemetry;
+mod report;
+mod shutdown;
+mod metrics;
+mod error;
+mod realtime;
+mod preload;
+mod check;
+
+use display::{DispmanxBackend, DisplayResolution};
+use backend::Backend;
+use fbdev::FbDevBackend;
+use kms::KmsBackend;
+use vsync::{VSyncSource, TimerVSync, FieldClock, DriftMeter, RateCorrection};
+use timer::RenderTimer;
 use playlist::Playlist;
-
-use std::{thread, io, fs, sync::Arc};
-use hound;
-use clap::Clap;
+use pcm::ControlWord;
+use encoder::{LineEncoder, LineSource};
+use simulator::{Simulator, ImpairmentProfile};
+use cvbs::{CvbsWriter, VideoStandard};
+use geometry::Geometry;
+use config::{Config, Profile, ModeDefinition};
+use levels::Levels;
+use pattern::{Pattern, PatternGenerator};
+use fields::{Field, FieldQueue, QueueDepth};
+use telemetry::{FieldTelemetry, FieldLog};
+use metrics::{Metrics, MetricsFormat};
+use realtime::{Scheduling, ThreadSettings};
+use preload::PreloadSettings;
+use error::{Error, Result};
+
+use std::{process, thread};
+use std::sync::{mpsc, Arc, Mutex};
+use clap::{Clap, ArgMatches, IntoApp, FromArgMatches};
 use thread_priority::*;
-use rb::*;
-
-const LEFT_OFFSET: i32 = 14;
-const TOP_OFFSET: i32 = 1;
-
-const PCM_LINE_PREAMBLE_BYTES: &'static [u8] = &[1, 0, 1, 0];
-const PCM_LINE_PREAMBLE_WIDTH: i32 = PCM_LINE_PREAMBLE_BYTES.len() as i32;
-const PCM_LINE_END_WHITE_REFERENCE_BYTES: &'static [u8] = &[0, 2, 2, 2, 2];
-const PCM_LINE_END_WHITE_REFERENCE_WIDTH: i32 = PCM_LINE_END_WHITE_REFERENCE_BYTES.len() as i32;
-
-const PCM_FULL_WIDTH: i32 = 137;
-const PCM_DATA_WIDTH: i32 = PCM_FULL_WIDTH - PCM_LINE_PREAMBLE_WIDTH - PCM_LINE_END_WHITE_REFERENCE_WIDTH;
 
-const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0};
-const GRAY: RGB8 = RGB8 { r: 153, g: 153, b: 153 };
-const WHITE: RGB8 = RGB8 { r: 255, g: 255, b: 255 };
-
-const DISPMANX_LAYER: i32 = 200;
+// Bits of a PCM line: 7 words, the S word and the CRC
+const PCM_DATA_WIDTH: i32 = 128;
 
 #[derive(Copy, Clone)]
 struct PCMMode {
@@ -56,201 +81,811 @@ impl PCMMode {
             pcm_data_lines_in_field: pcm_data_lines_in_field
         }
     }
+
+    fn set_line_counts(&mut self, pcm_data_lines_in_field: Option<i32>, visible_pcm_field_height: Option<i32>) {
+        if let Some(lines) = pcm_data_lines_in_field {
+            self.pcm_data_lines_in_field = lines;
+        }
+        if let Some(lines) = visible_pcm_field_height {
+            self.visible_pcm_field_height = lines;
+            self.visible_pcm_data_field_height = lines - 1;
+        }
+    }
 }
 
 #[derive(Clap)]
 #[clap(name="PiCM", version = "0.1.3", author = "István Nagy <nistvan.86@gmail.com>")]
 struct Opts {
-    /// .wav or .m3u file pointing to WAV files to be played.
-    input: String,
-    /// Print field render average times every second
+    /// .wav or .m3u file pointing to WAV files to be played, or a test signal like signal:tone:1000:-20.
+    /// Can be left out if the profile has one.
+    input: Option<String>,
+    /// Config file with the profiles [default: /etc/picm.toml, if it exists]
+    #[clap(long)]
+    config: Option<String>,
+    /// Profile from the config file to take the settings from
+    #[clap(long)]
+    profile: Option<String>,
+    /// Print field render time statistics every 10 seconds and at exit
     #[clap(short)]
     render_times: bool,
+    /// Output to show the fields on (dispmanx, fbdev, kms)
+    #[clap(long, default_value = "dispmanx")]
+    backend: String,
+    /// Framebuffer device (or plain file) for the fbdev backend
+    #[clap(long, default_value = "/dev/fb0")]
+    fb_device: String,
+    /// DRM device for the kms backend
+    #[clap(long, default_value = "/dev/dri/card0")]
+    drm_device: String,
+    /// Pace the fields with a software clock even if the backend has vsync
+    #[clap(long)]
+    software_vsync: bool,
+    /// Resample the audio to follow the measured field clock (e.g. 44056 Hz in NTSC mode)
+    #[clap(long)]
+    drift_correction: bool,
+    /// Write the times of every field (vsync, wakeup, rendering, submit) to a CSV file
+    #[clap(long)]
+    field_log: Option<String>,
+    /// Write the state of the encoder to this file every 10 seconds, for dashboards and alerts
+    #[clap(long)]
+    metrics: Option<String>,
+    /// Format of the metrics file: json (a line appended each time) or prometheus (textfile collector)
+    #[clap(long, default_value = "json")]
+    metrics_format: String,
+    /// Decode this many playlist items ahead into RAM, or all of them if they fit in the budget,
+    /// so the storage isn't read while playing
+    #[clap(long)]
+    preload: Option<String>,
+    /// Megabytes of RAM the preloaded audio may take up (a minute of audio is about 10 MB)
+    #[clap(long, default_value = "256")]
+    preload_budget: usize,
+    /// Scheduling of the draw thread: fifo:<1-99>, rr:<1-99>, fifo or rr for the highest priority,
+    /// or other [default: the highest priority of the current policy]
+    #[clap(long)]
+    draw_sched: Option<String>,
+    /// Scheduling of the producer thread encoding the audio, like --draw-sched
+    #[clap(long)]
+    producer_sched: Option<String>,
+    /// Scheduling of the control threads (the main thread and the metrics writer), like --draw-sched
+    #[clap(long)]
+    control_sched: Option<String>,
+    /// CPUs to pin the draw thread to, like 3 or 2,3 or 1-3
+    #[clap(long)]
+    draw_cpus: Option<String>,
+    /// CPUs to pin the producer thread to
+    #[clap(long)]
+    producer_cpus: Option<String>,
+    /// CPUs to pin the control threads to
+    #[clap(long)]
+    control_cpus: Option<String>,
+    /// Lock all memory into RAM (mlockall), so the threads are never held up by page faults
+    #[clap(long)]
+    lock_memory: bool,
+    /// Run the encoded fields through an impairment profile and decode them instead of displaying
+    /// (clean, beta, vhs, vhs-worn, tearing)
+    #[clap(long)]
+    simulate: Option<String>,
+    /// Write the composite video signal as raw 16 bit samples to this file instead of displaying
+    #[clap(long)]
+    cvbs: Option<String>,
+    /// Sample rate of the composite signal in Hz, or 4fsc
+    #[clap(long, default_value = "13500000")]
+    cvbs_rate: String,
+    /// Number of fields to simulate or write, even for --cvbs which writes whole frames
+    #[clap(long, default_value = "500")]
+    fields: u64,
+    /// PCM mode: pal, ntsc, custom or a mode defined in the config file. Detected from the
+    /// display resolution by default, headless backends fall back to pal.
+    #[clap(long)]
+    mode: Option<String>,
+    /// Screen width of the custom mode
+    #[clap(long)]
+    screen_width: Option<i32>,
+    /// Screen height of the custom mode
+    #[clap(long)]
+    screen_height: Option<i32>,
+    /// Field rate of the custom mode, 60 runs at 60000/1001 Hz
+    #[clap(long)]
+    field_rate: Option<i32>,
+    /// Blank pixels left of the PCM lines
+    #[clap(long, default_value = "14")]
+    left_offset: i32,
+    /// Blank rows above the first PCM line
+    #[clap(long, default_value = "1")]
+    top_offset: i32,
+    /// Bit cells in a PCM line, stretched over the screen width right of the left offset
+    #[clap(long, default_value = "137")]
+    line_width: i32,
+    /// Cells before the data bits, as palette indices (0 black, 1 gray, 2 white)
+    #[clap(long, default_value = "1010")]
+    preamble: String,
+    /// Cells ending the line, as palette indices
+    #[clap(long, default_value = "02222")]
+    white_reference: String,
+    /// PCM data lines in a field, including the ones not shown (294 for PAL, 245 for NTSC)
+    #[clap(long)]
+    lines_in_field: Option<i32>,
+    /// Lines shown in a field, including the control line (half the screen height by default)
+    #[clap(long)]
+    visible_lines: Option<i32>,
+    /// Framebuffer value of the blanking level, also used for data 0
+    #[clap(long, default_value = "0")]
+    black_level: u8,
+    /// Framebuffer value of data 1
+    #[clap(long, default_value = "153")]
+    data_level: u8,
+    /// Framebuffer value of the white reference
+    #[clap(long, default_value = "255")]
+    white_level: u8,
+    /// Show stepped level patches instead of playing, to measure the levels
+    #[clap(long)]
+    calibrate: bool,
+    /// Apply 50/15us pre-emphasis to the audio and flag it in the CTL line
+    #[clap(long)]
+    pre_emphasis: bool,
+    /// CTL flags separated by commas: copyright, emphasis (and no-copyright, no-emphasis).
+    /// A #PICM-CTL: line in the playlist changes them for the next item.
+    #[clap(long)]
+    ctl_flags: Option<String>,
+    /// Show a test pattern instead of the audio (bitclock, ones, zeros, lines, walking, counter)
+    #[clap(long)]
+    pattern: Option<String>,
+    /// Check the settings and read through every playlist item, reporting their durations,
+    /// then exit without touching the display
+    #[clap(long)]
+    check: bool,
 }
 
-fn paste(v: &mut Vec<u8>, x: usize, p: Vec<u8>) {
-    v.splice(x..x + p.len(), p);
+// The 8 pixels of every byte value, most significant bit first
+const BYTE_PIXELS: [[u8; 8]; 256] = get_byte_pixels();
+
+const fn get_byte_pixels() -> [[u8; 8]; 256] {
+    let mut table = [[0u8; 8]; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut bit = 0;
+        while bit < 8 {
+            table[byte][bit] = ((byte >> (7 - bit)) & 1) as u8;
+            bit += 1;
+        }
+        byte += 1;
+    }
+    table
 }
 
-fn get_base_line() -> Vec<u8> {
-    let mut line = vec![0u8; PCM_FULL_WIDTH as usize];
-    paste(&mut line, 0, Vec::from(PCM_LINE_PREAMBLE_BYTES));
-    paste(&mut line, PCM_FULL_WIDTH as usize - PCM_LINE_END_WHITE_REFERENCE_BYTES.len(), Vec::from(PCM_LINE_END_WHITE_REFERENCE_BYTES));
-    line
+/// Expands the bits of a line into the pixels of a row, a byte at a time
+fn bits_to_pixels(bits: u128, pixel_bytes: &mut [u8]) {
+    for (pixels, byte) in pixel_bytes[..PCM_DATA_WIDTH as usize].chunks_exact_mut(8).zip(bits.to_be_bytes().iter()) {
+        pixels.copy_from_slice(&BYTE_PIXELS[*byte as usize]);
+    }
 }
 
-fn next_stereo_samples(wav_samples: &mut hound::WavIntoSamples<io::BufReader<fs::File>, i32>) -> Option<[u16; 2]> {
-    let mut result = [0u16; 2];
+impl Opts {
+    fn input(&self) -> Result<&String> {
+        self.input.as_ref().ok_or_else(|| Error::Usage(String::from("No input given on the command line nor in the profile")))
+    }
+}
 
-    for i in 0..2 {
-        match wav_samples.next() {
-            Some(sample) => {
-                result[i] = sample.ok().unwrap() as u16;
-            },
-            None => return None
+fn apply_profile(opts: &mut Opts, matches: &ArgMatches, profile: &Profile) {
+    // Only fill in what wasn't given on the command line
+    macro_rules! from_profile {
+        ($field:ident) => {
+            if matches.occurrences_of(stringify!($field).replace('_', "-").as_str()) == 0 {
+                if let Some(value) = &profile.$field { opts.$field = value.clone(); }
+            }
         }
     }
 
-    Some(result)
+    if opts.input.is_none() { opts.input = profile.input.clone(); }
+    from_profile!(backend);
+    from_profile!(fb_device);
+    from_profile!(drm_device);
+    from_profile!(software_vsync);
+    from_profile!(drift_correction);
+    from_profile!(metrics_format);
+    from_profile!(lock_memory);
+    from_profile!(preload_budget);
+    from_profile!(pre_emphasis);
+    from_profile!(left_offset);
+    from_profile!(top_offset);
+    from_profile!(line_width);
+    from_profile!(preamble);
+    from_profile!(white_reference);
+    from_profile!(black_level);
+    from_profile!(data_level);
+    from_profile!(white_level);
+    if opts.ctl_flags.is_none() { opts.ctl_flags = profile.ctl_flags.clone(); }
+    if opts.metrics.is_none() { opts.metrics = profile.metrics.clone(); }
+    if opts.preload.is_none() { opts.preload = profile.preload.clone(); }
+    if opts.draw_sched.is_none() { opts.draw_sched = profile.draw_sched.clone(); }
+    if opts.producer_sched.is_none() { opts.producer_sched = profile.producer_sched.clone(); }
+    if opts.control_sched.is_none() { opts.control_sched = profile.control_sched.clone(); }
+    if opts.draw_cpus.is_none() { opts.draw_cpus = profile.draw_cpus.clone(); }
+    if opts.producer_cpus.is_none() { opts.producer_cpus = profile.producer_cpus.clone(); }
+    if opts.control_cpus.is_none() { opts.control_cpus = profile.control_cpus.clone(); }
+    if opts.mode.is_none() { opts.mode = profile.mode.clone(); }
+    if opts.screen_width.is_none() { opts.screen_width = profile.screen_width; }
+    if opts.screen_height.is_none() { opts.screen_height = profile.screen_height; }
+    if opts.field_rate.is_none() { opts.field_rate = profile.field_rate; }
+    if opts.lines_in_field.is_none() { opts.lines_in_field = profile.lines_in_field; }
+    if opts.visible_lines.is_none() { opts.visible_lines = profile.visible_lines; }
 }
 
-fn open_wave(file: String) -> hound::WavIntoSamples<io::BufReader<fs::File>, i32> {
-    println!("Opening WAV: {}", file);
-    let reader = hound::WavReader::open(file).unwrap();
-    let spec = reader.spec();
-    if spec.bits_per_sample != 16 || spec.sample_format != hound::SampleFormat::Int || spec.sample_rate != 44100 || spec.channels != 2 {
-        panic!("Currently only 44.1kHz Stereo 16 bit WAV files are supported.");
+fn apply_mode_definition(opts: &mut Opts, definition: &ModeDefinition) {
+    // A mode from the config file is a preset for the custom mode options
+    opts.mode = Some(String::from("custom"));
+    if opts.screen_width.is_none() { opts.screen_width = Some(definition.screen_width); }
+    if opts.screen_height.is_none() { opts.screen_height = Some(definition.screen_height); }
+    if opts.field_rate.is_none() { opts.field_rate = Some(definition.field_rate); }
+    if opts.lines_in_field.is_none() { opts.lines_in_field = Some(definition.lines_in_field); }
+    if opts.visible_lines.is_none() { opts.visible_lines = definition.visible_lines; }
+}
+
+fn get_opts() -> Result<Opts> {
+    let matches = Opts::into_app().get_matches();
+    let mut opts = Opts::from_arg_matches(&matches);
+
+    let config = Config::load(opts.config.as_ref())?;
+    if let Some((name, profile)) = config.get_profile(opts.profile.as_ref())? {
+        println!("Profile: {}", name);
+        apply_profile(&mut opts, &matches, profile);
     }
-    reader.into_samples::<i32>()
+    if let Some(definition) = opts.mode.as_ref().and_then(|name| config.mode.get(name)).cloned() {
+        apply_mode_definition(&mut opts, &definition);
+    }
+
+    // Fail early without an input
+    if !opts.calibrate && opts.pattern.is_none() { opts.input()?; }
+    Ok(opts)
 }
 
-fn bits_to_pixels(bits: u128, pixel_bytes: &mut [u8; 128]) {
-    const SOURCE_BITS: u8 = 128;
-    for b in 0..SOURCE_BITS {
-        pixel_bytes[b as usize] = if (bits & (1 << SOURCE_BITS-1-b)) > 0 { 1 } else { 0 };
+fn simulate(opts: &Opts, profile_name: &String) -> Result<()> {
+    let profile = ImpairmentProfile::from_name(profile_name)
+        .ok_or_else(|| Error::Usage(format!("Unknown impairment profile: {}, available: {}", profile_name, ImpairmentProfile::names().join(", "))))?;
+
+    let (mode, geometry) = get_picture(opts, get_mode(opts, None)?)?;
+    let mut source = open_line_source(opts, &mode, None)?;
+    let mut simulator = Simulator::new(mode, geometry, get_levels(opts)?, profile);
+
+    for _ in 0..opts.fields {
+        simulator.run_field(source.as_mut())?;
     }
+
+    simulator.report().print(&profile);
+    Ok(())
 }
 
-fn main() {
-    let opts: Opts = Opts::parse();
+fn write_cvbs(opts: &Opts, file: &String) -> Result<()> {
+    if opts.fields % 2 != 0 {
+        return Err(Error::Usage(format!("--cvbs writes whole frames of two fields, {} fields can't be written", opts.fields)));
+    }
+    let (mode, geometry) = get_picture(opts, get_mode(opts, None)?)?;
+    let sample_rate = VideoStandard::for_mode(&mode).parse_sample_rate(&opts.cvbs_rate)
+        .ok_or_else(|| Error::Usage(format!("Invalid CVBS sample rate: {}", opts.cvbs_rate)))?;
 
-    let display = Arc::new(Display::init(0));
+    let mut source = open_line_source(opts, &mode, None)?;
+    let mut writer = CvbsWriter::create(file, mode, geometry, get_levels(opts)?, sample_rate)?;
+    writer.print_timing();
 
-    // Try to figure out the PCM mode from current resolution
-    let resolution = display.get_resolution();
+    cvbs::render(source.as_mut(), &mut writer, &mode, opts.fields)
+}
 
-    let modes: Vec<PCMMode> = Vec::from([
+fn get_pcm_modes() -> Vec<PCMMode> {
+    Vec::from([
         PCMMode::new(720, 576, 50, 294), // PAL
         PCMMode::new(720, 480, 60, 245) // NTSC
-    ]);
+    ])
+}
+
+// The mode picked with --mode, None when it is up to the display
+fn get_requested_mode(opts: &Opts) -> Result<Option<PCMMode>> {
+    Ok(Some(match opts.mode.as_deref() {
+        Some("pal") => get_pcm_modes()[0],
+        Some("ntsc") => get_pcm_modes()[1],
+        Some("custom") => {
+            let required = |value: Option<i32>, option: &str| value.ok_or_else(|| Error::Usage(format!("--{} is needed for a custom mode", option)));
+            PCMMode::new(required(opts.screen_width, "screen-width")?, required(opts.screen_height, "screen-height")?,
+                required(opts.field_rate, "field-rate")?, required(opts.lines_in_field, "lines-in-field")?)
+        },
+        Some(name) => return Err(Error::Usage(format!("Unknown mode: {}, available: pal, ntsc, custom or a mode from the config file", name))),
+        None => return Ok(None)
+    }))
+}
 
-    let mut compatible_mode: Option<PCMMode> = None;
+fn get_mode(opts: &Opts, resolution: Option<DisplayResolution>) -> Result<PCMMode> {
+    let mode = match get_requested_mode(opts)? {
+        Some(mode) => mode,
+        None => match resolution {
+            Some(resolution) => {
+                // Try to figure out the PCM mode from current resolution
+                let mut compatible_mode: Option<PCMMode> = None;
+
+                for current_mode in get_pcm_modes() {
+                    if resolution.width == current_mode.screen_width && resolution.height == current_mode.screen_height {
+                        compatible_mode = Some(current_mode);
+                        break;
+                    }
+                }
 
-    for current_mode in modes {
-        if resolution.width == current_mode.screen_width && resolution.height == current_mode.screen_height {
-            compatible_mode = Some(current_mode);
-            break;
+                match compatible_mode {
+                    Some(mode) => mode,
+                    None => return Err(Error::Display(format!("Can't find a PCM mode for the current resolution: {}x{}, pick one with --mode", resolution.width, resolution.height)))
+                }
+            },
+            // There is no display to sniff the resolution from, assume PAL
+            None => get_pcm_modes()[0]
         }
-    }
+    };
 
-    if compatible_mode.is_none() {
-        panic!("Can't found a PCM mode for the current resolution: {}x{}", resolution.width, resolution.height);
+    if let Some(resolution) = resolution {
+        if resolution.width != mode.screen_width || resolution.height != mode.screen_height {
+            println!("Warning: the display is {}x{}, the mode is {}x{}", resolution.width, resolution.height, mode.screen_width, mode.screen_height);
+        }
     }
-    let mode = compatible_mode.unwrap();
+    println!("Mode: {}x{}, {} fields per second", mode.screen_width, mode.screen_height, mode.field_rate);
+
+    Ok(mode)
+}
 
-    let mut playlist = if opts.input.to_ascii_lowercase().ends_with(".m3u") {
-        Playlist::new_from_m3u(opts.input.clone())
+fn get_picture(opts: &Opts, mut mode: PCMMode) -> Result<(PCMMode, Geometry)> {
+    mode.set_line_counts(opts.lines_in_field, opts.visible_lines);
+
+    let geometry = Geometry {
+        left_offset: opts.left_offset,
+        top_offset: opts.top_offset,
+        full_width: opts.line_width,
+        preamble: geometry::parse_pattern(&opts.preamble).ok_or_else(|| Error::Usage(format!("Invalid preamble pattern: {}", opts.preamble)))?,
+        white_reference: geometry::parse_pattern(&opts.white_reference).ok_or_else(|| Error::Usage(format!("Invalid white reference pattern: {}", opts.white_reference)))?
+    };
+    geometry.validate(&mode).map_err(|error| Error::Usage(format!("Invalid picture geometry: {}", error)))?;
+    geometry.print(&mode);
+
+    Ok((mode, geometry))
+}
+
+fn get_levels(opts: &Opts) -> Result<Levels> {
+    let levels = Levels { black: opts.black_level, data: opts.data_level, white: opts.white_level };
+    levels.validate().map_err(|error| Error::Usage(format!("Invalid levels: {}", error)))?;
+    levels.print();
+    Ok(levels)
+}
+
+fn calibrate(opts: &Opts, mut backend: Box<dyn Backend>, mode: PCMMode, geometry: Geometry, levels: Levels) -> Result<()> {
+    backend.start(&mode, &geometry, &levels.get_calibration_palette())?;
+
+    let mut software_vsync: Option<Box<dyn VSyncSource>> = if opts.software_vsync || !backend.has_vsync() {
+        Some(Box::new(TimerVSync::new(&mode)))
     } else {
-        Playlist::new_with_single_item(opts.input.clone())
+        None
     };
 
-    let ring_buffer: SpscRb<u8> = SpscRb::new((mode.visible_pcm_data_field_height * PCM_DATA_WIDTH * mode.field_rate * 2) as usize);
-    let (ring_buffer_producer, ring_buffer_consumer) = (ring_buffer.producer(), ring_buffer.consumer());
+    loop {
+        match &mut software_vsync {
+            Some(vsync) => { vsync.wait(); },
+            None => { backend.wait_for_vsync()?; }
+        }
 
-    thread::spawn(move || {
-        let mut wav_samples = open_wave(playlist.next_file());
-        let mut pcm = PCMEngine::new();
+        levels::draw_calibration(backend.back_buffer());
+        backend.present()?;
+    }
+}
 
-        let mut current_line = 0;
-        let mut line_pixel_bytes = [0u8; PCM_DATA_WIDTH as usize];
+fn get_thread_settings(sched: &Option<String>, cpus: &Option<String>) -> Result<ThreadSettings> {
+    let scheduling = match sched {
+        Some(spec) => Some(Scheduling::parse(spec)
+            .ok_or_else(|| Error::Usage(format!("Invalid scheduling: {}, use fifo:<1-99>, rr:<1-99>, fifo, rr or other", spec)))?),
+        None => None
+    };
+    let cpus = match cpus {
+        Some(list) => Some(realtime::parse_cpus(list).ok_or_else(|| Error::Usage(format!("Invalid CPU list: {}", list)))?),
+        None => None
+    };
+    Ok(ThreadSettings { scheduling: scheduling, cpus: cpus })
+}
 
-        loop {
-            let stereo_sample = next_stereo_samples(&mut wav_samples);
-            let samples = if stereo_sample.is_none() {
-                wav_samples = open_wave(playlist.next_file()); // Move to next playlist item
-                next_stereo_samples(&mut wav_samples).unwrap()
-            } else {
-                stereo_sample.unwrap()
-            };
+fn open_playlist(input: &str) -> Result<Playlist> {
+    if input.to_ascii_lowercase().ends_with(".m3u") {
+        Playlist::new_from_m3u(String::from(input))
+    } else {
+        Ok(Playlist::new_with_single_item(String::from(input)))
+    }
+}
 
-            if let Some(line_data) = pcm.submit_stereo_sample(samples) {
-                if current_line < mode.visible_pcm_data_field_height {
-                    bits_to_pixels(line_data, &mut line_pixel_bytes);
-                    ring_buffer_producer.write_blocking(&line_pixel_bytes);
-                }
-                if current_line == mode.pcm_data_lines_in_field - 1 {
-                    current_line = 0;
-                } else {
-                    current_line += 1;
-                }
+fn get_control_word(opts: &Opts) -> Result<ControlWord> {
+    let mut control = ControlWord::new();
+    if let Some(flags) = &opts.ctl_flags {
+        control.apply_flags(flags).map_err(|e| Error::Usage(format!("Invalid CTL flags: {}", e)))?;
+    }
+    if opts.pre_emphasis && control.pre_emphasis {
+        return Err(Error::Usage(String::from("--pre-emphasis filters the audio, --ctl-flags emphasis says it is emphasized already, use one of them")));
+    }
+    Ok(control)
+}
+
+fn get_preload(opts: &Opts) -> Result<Option<PreloadSettings>> {
+    match &opts.preload {
+        Some(spec) => Ok(Some(PreloadSettings::parse(spec, opts.preload_budget)
+            .ok_or_else(|| Error::Usage(format!("Invalid preload: {}, use a number of items or all", spec)))?)),
+        None => Ok(None)
+    }
+}
+
+fn open_line_source(opts: &Opts, mode: &PCMMode, rate_correction: Option<RateCorrection>) -> Result<Box<dyn LineSource>> {
+    match &opts.pattern {
+        Some(name) => {
+            let pattern = Pattern::from_name(name)
+                .ok_or_else(|| Error::Usage(format!("Unknown test pattern: {}, available: {}", name, Pattern::names().join(", "))))?;
+            println!("Test pattern: {}", name);
+            Ok(Box::new(PatternGenerator::new(pattern, mode)))
+        },
+        None => {
+            let mut encoder = LineEncoder::new(open_playlist(opts.input()?)?, get_control_word(opts)?, get_preload(opts)?)?;
+            if let Some(correction) = rate_correction {
+                encoder.set_rate_correction(correction);
+            }
+            if opts.pre_emphasis {
+                encoder.set_pre_emphasis()?;
             }
+            Ok(Box::new(encoder))
         }
-    });
+    }
+}
 
-    display.set_bilinear_filtering(false);
+fn get_metrics_format(opts: &Opts) -> Result<MetricsFormat> {
+    MetricsFormat::from_name(&opts.metrics_format)
+        .ok_or_else(|| Error::Usage(format!("Unknown metrics format: {}, available: json, prometheus", opts.metrics_format)))
+}
 
-    let display_clone = display.clone();
+const BACKENDS: &'static [&'static str] = &["dispmanx", "fbdev", "kms"];
 
-    let draw_thread_handle = thread::spawn(move || {
-        set_current_thread_priority(ThreadPriority::Max).expect("Failed to set thread priority");
+// Goes through the settings like playing would, and every playlist item, without opening the display
+fn check(opts: &Opts) -> Result<()> {
+    if !BACKENDS.contains(&opts.backend.as_str()) {
+        return Err(Error::Usage(format!("Unknown backend: {}, available: {}", opts.backend, BACKENDS.join(", "))));
+    }
+    get_thread_settings(&opts.draw_sched, &opts.draw_cpus)?;
+    get_thread_settings(&opts.producer_sched, &opts.producer_cpus)?;
+    get_thread_settings(&opts.control_sched, &opts.control_cpus)?;
+    get_metrics_format(opts)?;
+    get_preload(opts)?;
+
+    if opts.mode.is_none() && opts.simulate.is_none() && opts.cvbs.is_none() {
+        println!("No --mode given, it is picked by the display resolution when playing");
+    }
+    let (mode, _) = get_picture(opts, get_mode(opts, None)?)?;
+    get_levels(opts)?;
 
-        let update = display.start_update(10);
+    if opts.calibrate {
+        return Ok(());
+    }
+    if opts.pattern.is_some() {
+        // Opening the pattern checks its name
+        open_line_source(opts, &mode, None)?;
+        return Ok(());
+    }
+    check::check_playlist(open_playlist(opts.input()?)?, get_control_word(opts)?, &mode, opts.drift_correction)
+}
 
-        let mut palette = Palette::from_colors(vec![BLACK, GRAY, WHITE]);
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("Error: {}", error);
+        process::exit(error.exit_code());
+    }
+}
 
-        // Synchronization frame
-        let mut sync_frame_resource = ImageResource::from_image(Image::new(ImageType::_8BPP, PCM_FULL_WIDTH, 1));
-        sync_frame_resource.set_palette(&mut palette);
-        let base_line = get_base_line();
-        sync_frame_resource.image.set_pixel_bytes(0, 0, &base_line);
-        sync_frame_resource.update();
+fn run() -> Result<()> {
+    let opts = get_opts()?;
 
-        let frame_rect = Rect { x: LEFT_OFFSET, y: TOP_OFFSET, width: mode.screen_width - LEFT_OFFSET, height: (mode.visible_pcm_field_height * 2) };
-        update.create_element_from_image_resource(DISPMANX_LAYER, frame_rect, &sync_frame_resource);
+    if opts.check {
+        return check(&opts);
+    }
 
-        // Data front and back buffer image resource
-        let mut data_resources: Vec<ImageResource> = Vec::new();
-        for _ in 0..2 {
-            let mut resource = ImageResource::from_image(Image::new(ImageType::_8BPP, PCM_DATA_WIDTH, mode.visible_pcm_field_height));
-            resource.set_palette(&mut palette);
-            resource.update();
-            data_resources.push(resource);
-        }
+    if let Some(profile_name) = &opts.simulate {
+        return simulate(&opts, profile_name);
+    }
 
-        let physical_pixel_width = (mode.screen_width - LEFT_OFFSET) as f32 / PCM_FULL_WIDTH as f32;
-        let data_physical_left_offset = (LEFT_OFFSET as f32 + physical_pixel_width * PCM_LINE_PREAMBLE_WIDTH as f32).round() as i32;
-        let data_physical_width = (physical_pixel_width * PCM_DATA_WIDTH as f32).round() as i32;
+    if let Some(file) = &opts.cvbs {
+        return write_cvbs(&opts, file);
+    }
 
-        let data_rect = Rect { x: data_physical_left_offset, y: TOP_OFFSET, width: data_physical_width, height: (mode.visible_pcm_field_height * 2) };
-        let data_element = update.create_element_from_image_resource(DISPMANX_LAYER + 1, data_rect, &data_resources[0]);
-        update.submit_sync();
+    let draw_settings = get_thread_settings(&opts.draw_sched, &opts.draw_cpus)?;
+    let producer_settings = get_thread_settings(&opts.producer_sched, &opts.producer_cpus)?;
+    let control_settings = get_thread_settings(&opts.control_sched, &opts.control_cpus)?;
+    let metrics_format = get_metrics_format(&opts)?;
 
-        let mut field_timer = if opts.render_times { Some(AvgPerformanceTimer::new(50)) } else { None };
+    let mut backend: Box<dyn Backend> = match opts.backend.as_str() {
+        "dispmanx" => Box::new(DispmanxBackend::init(0)?),
+        "fbdev" => Box::new(FbDevBackend::open(&opts.fb_device)?),
+        "kms" => Box::new(KmsBackend::open(&opts.drm_device, get_requested_mode(&opts)?)?),
+        _ => return Err(Error::Usage(format!("Unknown backend: {}, available: {}", opts.backend, BACKENDS.join(", "))))
+    };
 
-        let mut next_resource = 0;
-        let mut next_field_data = [0u8; PCM_DATA_WIDTH as usize];
+    let (mode, geometry) = get_picture(&opts, get_mode(&opts, backend.get_resolution())?)?;
+    let levels = get_levels(&opts)?;
 
-        // CTL, last 4 bits: no copyright, P-correction, no Q-correction (16bit mode), no pre-emph
-        let ctl_line = pcm::add_crc_to_data(0xCCCCCCCCCCCCCC000000000000000000u128 | (0b0011 << 16));
-        let mut ctl_pixel_bytes = [0u8; PCM_DATA_WIDTH as usize];
-        bits_to_pixels(ctl_line, &mut ctl_pixel_bytes);
+    if opts.calibrate {
+        return calibrate(&opts, backend, mode, geometry, levels);
+    }
+
+    // Before any thread is started, they take after the main thread. The producer and the
+    // draw thread go back to what picm was started with for what they are not given.
+    let started_with = ThreadSettings::of_current_thread();
+    let producer_settings = producer_settings.or(&started_with);
+    let raise_draw_priority = draw_settings.scheduling.is_none();
+    let draw_settings = draw_settings.or(&started_with);
+    control_settings.apply("control");
+    realtime::report("control");
+    if opts.lock_memory {
+        realtime::lock_memory();
+    }
+
+    // Two seconds of fields
+    let queue_capacity = (mode.field_rate * 2) as usize;
+    let (field_sender, field_receiver) = mpsc::sync_channel::<Field>(queue_capacity);
+    // Room for every line buffer there is: the queued fields, the one being encoded and the one shown
+    let (spent_sender, spent_receiver) = mpsc::sync_channel::<Field>(queue_capacity + 2);
+
+    let metrics = Arc::new(Mutex::new(Metrics { queue_capacity: queue_capacity as u64, ..Metrics::default() }));
+    if let Some(file) = &opts.metrics {
+        metrics::spawn_writer(file.clone(), metrics_format, metrics.clone());
+    }
+    let producer_metrics = metrics.clone();
+
+    let rate_correction = if opts.drift_correction { Some(RateCorrection::new()) } else { None };
+    let mut source = open_line_source(&opts, &mode, rate_correction.clone())?;
+    // Everything the draw thread has to tell is printed and logged by a thread of its own
+    let field_log = match &opts.field_log {
+        Some(file) => Some(FieldLog::create(file)?),
+        None => None
+    };
+    let render_timer = if opts.render_times || opts.metrics.is_some() { Some(RenderTimer::new(&mode, opts.render_times)) } else { None };
+    let (reporter, report_handle) = report::spawn(&mode, field_log, render_timer, metrics.clone());
+
+    let queue_depth = QueueDepth::new();
+    let mut field_queue = FieldQueue::new(field_receiver, spent_sender, queue_depth.clone(), mode, reporter.clone());
+    // The field after one going into a queue this deep can't be late unless the producer stalls
+    let silence_depth = mode.field_rate as u64;
+
+    let producer_handle = thread::spawn(move || -> Result<()> {
+        producer_settings.apply("producer");
+        realtime::report("producer");
+
+        let mut sequence = 0;
 
         loop {
-            thread::park(); // VSync handler wakes us up
-            if let Some(timer) = &mut field_timer { timer.begin(); }
+            // Whole fields at once, the lines beyond the visible area are dropped within the field
+            let mut field = spent_receiver.try_recv().unwrap_or_else(|_| Field::new(&mode));
+            if queue_depth.ahead_of(sequence) < silence_depth {
+                source.take_field_with_silence(&mode, &mut field)?;
+            } else {
+                source.take_field(&mode, &mut field.lines)?;
+                field.without_silence();
+            }
+            field.sequence = sequence;
+            if field_sender.send(field).is_err() { break; }
+            sequence += 1;
+
+            let mut metrics = producer_metrics.lock().unwrap();
+            metrics.fields_encoded = sequence;
+            if let Some((track, position)) = source.now_playing() {
+                if metrics.track.as_deref() != Some(track) { metrics.track = Some(String::from(track)); }
+                metrics.track_position = Some(position);
+            }
+        }
+        Ok(())
+    });
 
-            let update = display.start_update(10);
+    shutdown::handle_signals();
 
-            next_resource = if next_resource == 1 { 0 } else { 1 };
+    let draw_thread_handle = thread::spawn(move || -> Result<()> {
+        draw_settings.apply("draw");
+        if raise_draw_priority {
+            if let Err(error) = set_current_thread_priority(ThreadPriority::Max) {
+                println!("Warning: cannot raise the priority of the draw thread: {:?}", error);
+            }
+        }
+        realtime::report("draw");
+
+        backend.start(&mode, &geometry, &levels.get_palette())?;
+
+        let mut software_vsync: Option<Box<dyn VSyncSource>> = if opts.software_vsync || !backend.has_vsync() {
+            Some(Box::new(TimerVSync::new(&mode)))
+        } else {
+            None
+        };
+        let mut field_clock = FieldClock::new(&mode);
+        let mut drift_meter = if opts.drift_correction || opts.render_times || opts.metrics.is_some() { Some(DriftMeter::new(&mode, rate_correction, reporter.clone())) } else { None };
+        let mut telemetry = FieldTelemetry::new(&mode, reporter);
+
+        // Checked at the top, a field repeated for a double wakeup goes round without drawing
+        while !shutdown::stop_requested() {
+            let vblank = match &mut software_vsync {
+                Some(vsync) => Some(vsync.wait()),
+                None => backend.wait_for_vsync()?
+            };
 
-            for h in 0..mode.visible_pcm_field_height {
-                if h == 0 {
-                    data_resources[next_resource].image.set_pixel_bytes(0, h, &Vec::from(ctl_pixel_bytes));
-                } else {
-                    ring_buffer_consumer.read_blocking(&mut next_field_data);
-                    data_resources[next_resource].image.set_pixel_bytes(0, h, &Vec::from(next_field_data));
-                }
+            let clock_fields = field_clock.tick(vblank);
+            telemetry.wakeup(field_clock.vsync_ns(), clock_fields);
+            if let Some(meter) = &mut drift_meter {
+                for _ in 0..clock_fields { meter.field(); }
             }
+            // Still the same field on the clock, the one on screen stays
+            if clock_fields == 0 { continue; }
+            // The previous field stayed on screen for the missed ones, keep the rest on the clock
+            for _ in 1..clock_fields { field_queue.skip_field(); }
 
-            data_resources[next_resource].update();
-            update.replace_element_source(&data_element, &data_resources[next_resource]);
-            update.submit();
+            telemetry.render_start();
 
-            if let Some(timer) = &mut field_timer { timer.end(); }
+            // The encoder stopped, its thread tells why
+            let (sequence, lines) = match field_queue.next_field() {
+                Some(field) => field,
+                None => break
+            };
+            let image = backend.back_buffer();
+
+            // Straight into the rows of the image
+            for (h, line_data) in lines.iter().enumerate() {
+                bits_to_pixels(*line_data, image.get_row_mut(h as i32));
+            }
+            telemetry.render_end();
+
+            backend.present()?;
+            telemetry.submitted(sequence);
+
+            // Skipped when the writer has it, the next field brings it up to date
+            if let Ok(mut metrics) = metrics.try_lock() {
+                metrics.fields_shown = field_queue.fields_received();
+                metrics.underruns = field_queue.underruns();
+                metrics.silent_fields = field_queue.silent_fields();
+                metrics.missed_vsyncs = telemetry.missed_vsyncs();
+                metrics.late_submits = telemetry.late_submits();
+                metrics.double_wakeups = telemetry.double_wakeups();
+                metrics.drift_ppm = drift_meter.as_ref().and_then(|meter| meter.drift_ppm());
+            }
         }
+
+        telemetry.finish();
+        Ok(())
     });
 
-    let mut vsync_data = VSyncData { draw_thread: &draw_thread_handle.thread() };
-    display_clone.start_vsync_handler(&mut vsync_data);
-    draw_thread_handle.join().unwrap();
+    let drawn = draw_thread_handle.join().unwrap();
+    // The draw thread had every reporter, the reporting ends along with it
+    report_handle.join().unwrap();
+    drawn?;
+    if shutdown::stop_requested() {
+        return Ok(());
+    }
+    producer_handle.join().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hint::black_box;
+    use std::time::Instant;
+
+    const PROFILE: &str = r#"
+        input = "profile.m3u"
+        backend = "kms"
+        fb-device = "/dev/fb1"
+        drm-device = "/dev/dri/card1"
+        software-vsync = true
+        drift-correction = true
+        metrics-format = "prometheus"
+        lock-memory = true
+        preload-budget = 64
+        pre-emphasis = true
+        left-offset = 10
+        top-offset = 2
+        line-width = 140
+        preamble = "1100"
+        white-reference = "0222"
+        black-level = 5
+        data-level = 140
+        white-level = 250
+        mode = "ntsc"
+        lines-in-field = 240
+    "#;
+
+    fn opts_with_profile(args: &[&str]) -> Opts {
+        let matches = Opts::into_app().get_matches_from(std::iter::once("picm").chain(args.iter().copied()));
+        let mut opts = Opts::from_arg_matches(&matches);
+        let profile: Profile = toml::from_str(PROFILE).unwrap();
+        apply_profile(&mut opts, &matches, &profile);
+        opts
+    }
+
+    #[test]
+    fn profile_beats_the_defaults() {
+        let opts = opts_with_profile(&[]);
+        assert_eq!(opts.input.as_deref(), Some("profile.m3u"));
+        assert_eq!(opts.backend, "kms");
+        assert_eq!(opts.fb_device, "/dev/fb1");
+        assert_eq!(opts.drm_device, "/dev/dri/card1");
+        assert!(opts.software_vsync && opts.drift_correction && opts.lock_memory && opts.pre_emphasis);
+        assert_eq!(opts.metrics_format, "prometheus");
+        assert_eq!(opts.preload_budget, 64);
+        assert_eq!((opts.left_offset, opts.top_offset, opts.line_width), (10, 2, 140));
+        assert_eq!((opts.preamble.as_str(), opts.white_reference.as_str()), ("1100", "0222"));
+        assert_eq!((opts.black_level, opts.data_level, opts.white_level), (5, 140, 250));
+        assert_eq!(opts.mode.as_deref(), Some("ntsc"));
+        assert_eq!(opts.lines_in_field, Some(240));
+    }
+
+    #[test]
+    fn command_line_beats_the_profile() {
+        // Given on the command line with the default values, which still win
+        let opts = opts_with_profile(&["--backend", "dispmanx", "--fb-device", "/dev/fb0", "--drm-device", "/dev/dri/card0",
+            "--metrics-format", "json", "--preload-budget", "256", "--left-offset", "14", "--top-offset", "1", "--line-width", "137",
+            "--preamble", "1010", "--white-reference", "02222", "--black-level", "0", "--data-level", "153", "--white-level", "255",
+            "--mode", "pal", "--lines-in-field", "294", "cli.wav"]);
+        assert_eq!(opts.input.as_deref(), Some("cli.wav"));
+        assert_eq!(opts.backend, "dispmanx");
+        assert_eq!(opts.fb_device, "/dev/fb0");
+        assert_eq!(opts.drm_device, "/dev/dri/card0");
+        assert_eq!(opts.metrics_format, "json");
+        assert_eq!(opts.preload_budget, 256);
+        assert_eq!((opts.left_offset, opts.top_offset, opts.line_width), (14, 1, 137));
+        assert_eq!((opts.preamble.as_str(), opts.white_reference.as_str()), ("1010", "02222"));
+        assert_eq!((opts.black_level, opts.data_level, opts.white_level), (0, 153, 255));
+        assert_eq!(opts.mode.as_deref(), Some("pal"));
+        assert_eq!(opts.lines_in_field, Some(294));
+
+        let opts = opts_with_profile(&["--left-offset", "20"]);
+        assert_eq!((opts.left_offset, opts.top_offset), (20, 2));
+    }
+
+    // A bit at a time, what the byte table replaced
+    fn bits_to_pixels_by_bit(bits: u128, pixel_bytes: &mut [u8]) {
+        for b in 0..PCM_DATA_WIDTH as usize {
+            pixel_bytes[b] = ((bits >> (PCM_DATA_WIDTH as usize - 1 - b)) & 1) as u8;
+        }
+    }
+
+    fn test_lines() -> Vec<u128> {
+        let mut line = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210u128;
+        (0..1000).map(|_| { line = line.rotate_left(7) ^ (line >> 3); line }).collect()
+    }
+
+    #[test]
+    fn bits_to_pixels_matches_bit_by_bit() {
+        let mut pixels = [0u8; PCM_DATA_WIDTH as usize];
+        let mut expected = [0u8; PCM_DATA_WIDTH as usize];
+        for line in test_lines().into_iter().chain([0, u128::MAX, 1, 1 << 127].iter().copied()) {
+            bits_to_pixels(line, &mut pixels);
+            bits_to_pixels_by_bit(line, &mut expected);
+            assert!(pixels[..] == expected[..], "line {:032x}", line);
+        }
+    }
+
+    // cargo test --release -- --ignored --nocapture, on the Pi itself to mean anything
+    #[test]
+    #[ignore]
+    fn measure_bits_to_pixels() {
+        const ROUNDS: usize = 1000;
+        let lines = test_lines();
+        let mut pixels = [0u8; PCM_DATA_WIDTH as usize];
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            for line in &lines {
+                bits_to_pixels_by_bit(black_box(*line), &mut pixels);
+                black_box(&mut pixels);
+            }
+        }
+        let by_bit = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            for line in &lines {
+                bits_to_pixels(black_box(*line), &mut pixels);
+                black_box(&mut pixels);
+            }
+        }
+        let by_table = start.elapsed();
+
+        let count = (ROUNDS * lines.len()) as f64;
+        println!("Pixel expansion per line: bit by bit {:.1} ns, by table {:.1} ns",
+            by_bit.as_nanos() as f64 / count, by_table.as_nanos() as f64 / count);
+    }
 }
@@ -0,0 +1,127 @@
+use crate::timer::RenderSummary;
+
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+const WRITE_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum MetricsFormat {
+    /// A JSON object per line, appended every time
+    JsonLines,
+    /// Prometheus textfile collector file, replaced every time
+    Prometheus
+}
+
+impl MetricsFormat {
+    pub fn from_name(name: &str) -> Option<MetricsFormat> {
+        match name {
+            "json" => Some(MetricsFormat::JsonLines),
+            "prometheus" => Some(MetricsFormat::Prometheus),
+            _ => None
+        }
+    }
+}
+
+/// State of the encoder and the field clock, updated by the producer and the draw threads
+#[derive(Clone, Default, Serialize)]
+pub struct Metrics {
+    /// Seconds since the Unix epoch, when written
+    pub time: u64,
+    /// Playlist item being encoded, and how far in it is in seconds
+    pub track: Option<String>,
+    pub track_position: Option<f64>,
+    pub fields_encoded: u64,
+    pub fields_shown: u64,
+    /// Fields encoded but not shown yet, and how many fit in the queue
+    pub queued_fields: u64,
+    pub queue_capacity: u64,
+    pub underruns: u64,
+    pub silent_fields: u64,
+    pub missed_vsyncs: u64,
+    pub late_submits: u64,
+    pub double_wakeups: u64,
+    /// Over the last 10 seconds
+    pub render_times: Option<RenderSummary>,
+    pub drift_ppm: Option<f64>
+}
+
+pub type SharedMetrics = Arc<Mutex<Metrics>>;
+
+/// Writes the metrics to a file every 10 seconds from a thread of its own, for dashboards
+/// and alerts when picm runs unattended
+pub fn spawn_writer(file: String, format: MetricsFormat, metrics: SharedMetrics) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(WRITE_INTERVAL);
+
+            let mut snapshot = metrics.lock().unwrap().clone();
+            snapshot.time = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+            snapshot.queued_fields = snapshot.fields_encoded.saturating_sub(snapshot.fields_shown);
+
+            let written = match format {
+                MetricsFormat::JsonLines => write_json_line(&file, &snapshot),
+                MetricsFormat::Prometheus => write_prometheus(&file, &snapshot)
+            };
+            // The recording goes on, the next write may work again
+            if let Err(error) = written {
+                println!("Warning: cannot write metrics file {}: {}", file, error);
+            }
+        }
+    });
+}
+
+fn write_json_line(file: &String, metrics: &Metrics) -> io::Result<()> {
+    let mut output = OpenOptions::new().create(true).append(true).open(file)?;
+    writeln!(output, "{}", serde_json::to_string(metrics).unwrap())
+}
+
+fn write_prometheus(file: &String, metrics: &Metrics) -> io::Result<()> {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+        text += &format!("# HELP picm_{} {}\n# TYPE picm_{} {}\n", name, help, name, kind);
+        for (labels, value) in samples {
+            text += &format!("picm_{}{} {}\n", name, labels, value);
+        }
+    };
+    let single = |value: f64| vec![(String::new(), value)];
+
+    if let Some(track) = &metrics.track {
+        metric("track_info", "gauge", "Playlist item being encoded", vec![(format!("{{track=\"{}\"}}", escape_label(track)), 1.0)]);
+    }
+    if let Some(position) = metrics.track_position {
+        metric("track_position_seconds", "gauge", "Position in the playlist item being encoded", single(position));
+    }
+    metric("fields_encoded_total", "counter", "Fields encoded", single(metrics.fields_encoded as f64));
+    metric("fields_shown_total", "counter", "Fields taken by the draw thread", single(metrics.fields_shown as f64));
+    metric("queued_fields", "gauge", "Fields encoded but not shown yet", single(metrics.queued_fields as f64));
+    metric("queue_capacity_fields", "gauge", "Fields fitting in the queue", single(metrics.queue_capacity as f64));
+    metric("underruns_total", "counter", "Times the encoder fell behind", single(metrics.underruns as f64));
+    metric("silent_fields_total", "counter", "Fields of silence filled in", single(metrics.silent_fields as f64));
+    metric("missed_vsyncs_total", "counter", "Vsyncs the draw thread missed", single(metrics.missed_vsyncs as f64));
+    metric("late_submits_total", "counter", "Fields handed over after the next vsync", single(metrics.late_submits as f64));
+    metric("double_wakeups_total", "counter", "Wakeups within the same field", single(metrics.double_wakeups as f64));
+    if let Some(render) = &metrics.render_times {
+        // Gauges of the last interval, not a summary, which would need the sum and count of
+        // every field. The quantile label belongs to summaries, so these have their own.
+        let percentiles = [("50", render.p50_ms), ("95", render.p95_ms), ("99", render.p99_ms), ("99.9", render.p99_9_ms), ("100", render.max_ms)];
+        metric("render_time_seconds", "gauge", "Render time percentiles over the last 10 seconds",
+            percentiles.iter().map(|(percentile, ms)| (format!("{{percentile=\"{}\"}}", percentile), ms / 1000.0)).collect());
+        metric("render_overruns", "gauge", "Fields over the field period in the last 10 seconds", single(render.overruns as f64));
+    }
+    if let Some(drift) = metrics.drift_ppm {
+        metric("drift_ppm", "gauge", "Sample clock drift of the field clock", single(drift));
+    }
+
+    // The collector may read at any time, so the file is replaced in one go
+    let temporary = format!("{}.tmp", file);
+    File::create(&temporary)?.write_all(text.as_bytes())?;
+    fs::rename(&temporary, file)
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,87 @@
+use crate::PCMMode;
+use crate::encoder::LineSource;
+use crate::pcm;
+use crate::error::Result;
+
+#[derive(Copy, Clone)]
+pub enum Pattern {
+    /// 1010... over the whole line
+    BitClock,
+    Ones,
+    Zeros,
+    /// Every word holds the number of the line in the field, with a valid CRC
+    LineNumber,
+    /// A single 1 moving one bit further on every line
+    WalkingBit,
+    /// The field counter in the words of every line, with a valid CRC
+    Counter
+}
+
+const PATTERNS: &'static [(&'static str, Pattern)] = &[
+    ("bitclock", Pattern::BitClock),
+    ("ones", Pattern::Ones),
+    ("zeros", Pattern::Zeros),
+    ("lines", Pattern::LineNumber),
+    ("walking", Pattern::WalkingBit),
+    ("counter", Pattern::Counter)
+];
+
+impl Pattern {
+    pub fn from_name(name: &str) -> Option<Pattern> {
+        PATTERNS.iter().find(|(n, _)| *n == name).map(|(_, pattern)| *pattern)
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        PATTERNS.iter().map(|(name, _)| *name).collect()
+    }
+}
+
+// Seven data words and the S word, 14 bits each, all set to the same value
+fn repeat_word(word: u16) -> u128 {
+    let word = (word & 0x3FFF) as u128;
+    (0..8).fold(0u128, |data, _| (data << 14) | word) << 16
+}
+
+/// Diagnostic lines in place of the encoded audio, to have known content on the screen
+/// when a deck or a capture path misbehaves.
+pub struct PatternGenerator {
+    pattern: Pattern,
+    lines_in_field: i32,
+    line: i32,
+    field: u64,
+    bit: u32
+}
+
+impl PatternGenerator {
+    pub fn new(pattern: Pattern, mode: &PCMMode) -> Self {
+        PatternGenerator {
+            pattern: pattern,
+            lines_in_field: mode.pcm_data_lines_in_field,
+            line: 0,
+            field: 0,
+            bit: 0
+        }
+    }
+}
+
+impl LineSource for PatternGenerator {
+    fn next_line(&mut self) -> Result<u128> {
+        let line_data = match self.pattern {
+            Pattern::BitClock => u128::MAX / 3 * 2,
+            Pattern::Ones => u128::MAX,
+            Pattern::Zeros => 0,
+            Pattern::LineNumber => pcm::add_crc_to_data(repeat_word(self.line as u16)),
+            Pattern::WalkingBit => 1u128 << (127 - self.bit),
+            Pattern::Counter => pcm::add_crc_to_data((self.field as u128) << 16)
+        };
+
+        self.bit = (self.bit + 1) % 128;
+        self.line += 1;
+        if self.line == self.lines_in_field {
+            self.line = 0;
+            self.field += 1;
+        }
+
+        Ok(line_data)
+    }
+}
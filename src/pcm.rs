@@ -8,21 +8,21 @@ fn get_crc16_ccitt_false(data: u128, bits: u8) -> u16 {
 
     let mut cursor = bits;
     loop {
-        let mask = 0xff << cursor - 8;
-        let mut byte = ((data & mask) >> cursor - 8) as u16;
+        let mask = 0xff << (cursor - 8);
+        let mut byte = ((data & mask) >> (cursor - 8)) as u16;
 
-        byte = byte << 8;
+        byte <<= 8;
 
         for _ in 0..8 {
             let xor_flag = ((crc ^ byte) & 0x8000) > 0;
 
-            crc = crc << 1;
+            crc <<= 1;
 
-            if xor_flag { 
-                crc = crc ^ CRC16_CCITT_POLY;
+            if xor_flag {
+                crc ^= CRC16_CCITT_POLY;
             }
 
-            byte = byte << 1;
+            byte <<= 1;
         }
         
         if cursor == 8 { break; }
@@ -36,60 +36,126 @@ pub fn add_crc_to_data(data: u128) -> u128 {
     data | get_crc16_ccitt_false(data >> 16, 112) as u128
 }
 
-struct FIFODelayer {
-    buf: Vec<u16>,
+const CTL_SYNC_PATTERN: u128 = 0xCCCCCCCCCCCCCC000000000000000000;
+
+/// Contents of the CTL line, the first line of every field. The encoder always writes
+/// 16 bit words with P correction, so the CTL line always says so.
+#[derive(Copy, Clone, PartialEq)]
+pub struct ControlWord {
+    /// Copying prohibited
+    pub copyright: bool,
+    /// The audio is pre-emphasized with 50/15us
+    pub pre_emphasis: bool
 }
 
-impl FIFODelayer {
-    fn new(delay_length: u8) -> Self {
-        FIFODelayer {
-            buf: vec![0u16; (delay_length + 1) as usize]
+impl ControlWord {
+    pub fn new() -> Self {
+        ControlWord {
+            copyright: false,
+            pre_emphasis: false
         }
     }
 
-    fn feed(&mut self, sample: u16) {
-        if self.buf.len() > 1 { self.buf.rotate_right(1); }
-        self.buf[0] = sample;
+    /// Changes the flags listed separated by commas: copyright, no-copyright, emphasis,
+    /// no-emphasis. p and 16bit are accepted as what is written anyway.
+    pub fn apply_flags(&mut self, flags: &str) -> Result<(), String> {
+        for flag in flags.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()) {
+            match flag {
+                "copyright" => self.copyright = true,
+                "no-copyright" => self.copyright = false,
+                "emphasis" => self.pre_emphasis = true,
+                "no-emphasis" => self.pre_emphasis = false,
+                "p" | "16bit" => (),
+                "no-p" | "14bit" => return Err(format!("{} is not supported, the encoder always writes 16 bit words with P correction", flag)),
+                _ => return Err(format!("unknown CTL flag: {}", flag))
+            }
+        }
+        Ok(())
     }
 
-    fn get_output(&self) -> u16 {
-        self.buf[self.buf.len()-1]
+    pub fn to_line(self) -> u128 {
+        // Last 4 bits: copyright, P correction, Q correction, pre-emphasis. The last three are
+        // active low, the Q bit set means 16 bit words
+        let flags = (self.copyright as u128) << 3
+            | 1 << 1
+            | !self.pre_emphasis as u128;
+        add_crc_to_data(CTL_SYNC_PATTERN | (flags << 16))
     }
 }
 
+// Word d of a line is delayed by d*16 lines, so a line carries words of 7 different blocks
+const INTERLEAVE_DELAY: usize = 16;
+const INTERLEAVE_HISTORY: usize = 6 * INTERLEAVE_DELAY + 1;
+/// Lines carrying words of the lines before them, from there on a line only holds what was
+/// submitted since
+pub const INTERLEAVE_SPAN: usize = 6 * INTERLEAVE_DELAY;
+
+// The delay lines of all 7 words in one circular memory, a line of words per slot. Written
+// in place and read back through the cursor, nothing moves around as lines go by.
+#[derive(Clone)]
+struct InterleaveMemory {
+    buf: [[u16; 7]; INTERLEAVE_HISTORY],
+    cursor: usize
+}
+
+impl InterleaveMemory {
+    fn new() -> Self {
+        InterleaveMemory {
+            buf: [[0u16; 7]; INTERLEAVE_HISTORY],
+            cursor: 0
+        }
+    }
+
+    fn write(&mut self, word: usize, sample: u16) {
+        self.buf[self.cursor][word] = sample;
+    }
+
+    /// The word written d*16 lines ago, d being the word's position in the line
+    fn read(&self, word: usize) -> u16 {
+        let slot = (self.cursor + INTERLEAVE_HISTORY - word * INTERLEAVE_DELAY) % INTERLEAVE_HISTORY;
+        self.buf[slot][word]
+    }
+
+    fn next_line(&mut self) {
+        self.cursor = (self.cursor + 1) % INTERLEAVE_HISTORY;
+    }
+}
+
+#[derive(Clone)]
 pub struct PCMEngine {
-    lines: Vec<FIFODelayer>,
+    interleave: InterleaveMemory,
     current_line_input: usize,
     last_three_stereo_samples: Vec<[u16; 2]>
 }
 
 impl PCMEngine {
     pub fn new() -> Self {
-        let mut lines: Vec<FIFODelayer> = Vec::with_capacity(7);
-        for d in 0..7 {
-            lines.push(FIFODelayer::new(d * 16));
-        }
-
         PCMEngine {
-            lines: lines,
+            interleave: InterleaveMemory::new(),
             current_line_input: 0,
             last_three_stereo_samples: Vec::with_capacity(3)
         }
     }
 
+    /// The engine as it would be after silence went through the whole interleave, to encode
+    /// what comes next the way it goes on after silence
+    pub fn flushed(&self) -> Self {
+        let mut engine = self.clone();
+        engine.interleave.buf = [[0u16; 7]; INTERLEAVE_HISTORY];
+        engine
+    }
+
     fn get_current_line_data(&self) -> u128 {
+        // The top 14 bits of the words one after the other, then their low 2 bits together
+        // in the S word, 2 bits apiece, leaving 16 bits for the CRC
         let mut data = 0u128;
-        for d in 0..7 { // 14 bit words
-            let value = ((self.lines[d].get_output() >> 2) as u128) << (128 - 14*(d+1));
-            data = data | value
-        }
-
-        let mut s_word = 0u16;
-        for d in 0..7 { // 2 bit words (multiplexed into a single S word)
-            let data = (self.lines[d].get_output() & 0x3) << (14 - 2*(d+1));
-            s_word = s_word | data;
+        let mut s_word = 0u128;
+        for d in 0..7 {
+            let word = self.interleave.read(d) as u128;
+            data = (data << 14) | (word >> 2);
+            s_word = (s_word << 2) | (word & 0x3);
         }
-        data = data | ((s_word as u128) << 128 - 14*8);
+        data = (data << 14 | s_word) << 16;
 
         add_crc_to_data(data)
     }
@@ -105,7 +171,7 @@ impl PCMEngine {
 
     pub fn submit_stereo_sample(&mut self, stereo_sample: [u16; 2]) -> Option<u128> {
         for sample in &stereo_sample { 
-            self.lines[self.current_line_input].feed(*sample);
+            self.interleave.write(self.current_line_input, *sample);
             self.current_line_input += 1; 
         }
         self.last_three_stereo_samples.push(stereo_sample);
@@ -113,15 +179,276 @@ impl PCMEngine {
         if self.last_three_stereo_samples.len() == 3 {
             // We need to calculate an additional P word
             let p_value = self.get_p_value();
-            self.lines[self.current_line_input].feed(p_value);
+            self.interleave.write(self.current_line_input, p_value);
 
             self.last_three_stereo_samples.clear();
             self.current_line_input = 0;
 
-            Some(self.get_current_line_data())
+            let line_data = self.get_current_line_data();
+            self.interleave.next_line();
+            Some(line_data)
         } else {
             None
         }
     }
 
-}
\ No newline at end of file
+}
+
+fn split_line_data(data: u128) -> [u16; 7] {
+    let mut words = [0u16; 7];
+    let s_word = ((data >> (128 - 14*8)) & 0x3fff) as u16;
+    for (d, word) in words.iter_mut().enumerate() {
+        let high = ((data >> (128 - 14*(d+1))) & 0x3fff) as u16;
+        let low = (s_word >> (14 - 2*(d+1))) & 0x3;
+        *word = (high << 2) | low;
+    }
+    words
+}
+
+pub fn is_crc_valid(data: u128) -> bool {
+    add_crc_to_data(data & !0xffffu128) == data
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum SampleStatus {
+    Valid,
+    Corrected,
+    Lost
+}
+
+#[derive(Copy, Clone)]
+struct DecodedLine {
+    words: [u16; 7],
+    valid: bool
+}
+
+#[derive(Default, Copy, Clone)]
+pub struct DecoderStats {
+    pub lines: u64,
+    pub crc_errors: u64,
+    pub samples: u64,
+    pub corrected_samples: u64,
+    pub lost_samples: u64
+}
+
+pub struct DecodedBlock {
+    pub samples: [u16; 6],
+    pub status: [SampleStatus; 6]
+}
+
+// Reverses the interleave of PCMEngine: word d of a block is delayed by d*16 lines
+pub struct PCMDecoder {
+    history: Vec<DecodedLine>,
+    cursor: usize,
+    filled: usize,
+    pub stats: DecoderStats
+}
+
+impl PCMDecoder {
+    pub fn new() -> Self {
+        PCMDecoder {
+            history: vec![DecodedLine { words: [0u16; 7], valid: false }; INTERLEAVE_HISTORY],
+            cursor: 0,
+            filled: 0,
+            stats: DecoderStats::default()
+        }
+    }
+
+    /// Submits a line read from the picture, or None when the line was not available at all.
+    pub fn submit_line(&mut self, line: Option<u128>) -> Option<DecodedBlock> {
+        let decoded = match line {
+            Some(data) if is_crc_valid(data) => DecodedLine { words: split_line_data(data), valid: true },
+            _ => DecodedLine { words: [0u16; 7], valid: false }
+        };
+
+        self.stats.lines += 1;
+        if !decoded.valid { self.stats.crc_errors += 1; }
+
+        self.history[self.cursor] = decoded;
+        self.cursor = (self.cursor + 1) % INTERLEAVE_HISTORY;
+        if self.filled < INTERLEAVE_HISTORY {
+            self.filled += 1;
+            if self.filled < INTERLEAVE_HISTORY { return None; }
+        }
+
+        // self.cursor now points to the oldest line, which holds the first word of the block
+        let mut words = [0u16; 7];
+        let mut valid = [false; 7];
+        for d in 0..7 {
+            let line = &self.history[(self.cursor + d * INTERLEAVE_DELAY) % INTERLEAVE_HISTORY];
+            words[d] = line.words[d];
+            valid[d] = line.valid;
+        }
+
+        let invalid_count = valid.iter().filter(|v| !**v).count();
+        let mut block = DecodedBlock { samples: [0u16; 6], status: [SampleStatus::Valid; 6] };
+
+        for d in 0..6 {
+            block.samples[d] = words[d];
+            if !valid[d] {
+                if invalid_count == 1 {
+                    // Single error in the block, restore it from the P word
+                    let mut p = words[6];
+                    for (o, word) in words[..6].iter().enumerate() {
+                        if o != d { p ^= word; }
+                    }
+                    block.samples[d] = p;
+                    block.status[d] = SampleStatus::Corrected;
+                    self.stats.corrected_samples += 1;
+                } else {
+                    block.status[d] = SampleStatus::Lost;
+                    self.stats.lost_samples += 1;
+                }
+            }
+        }
+        self.stats.samples += 6;
+
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::Rng;
+    use std::hint::black_box;
+    use std::time::Instant;
+
+    // The delay line the interleave was built from: the buffer shifted along on every word
+    struct RotatingDelayer {
+        buf: Vec<u16>
+    }
+
+    impl RotatingDelayer {
+        fn new(delay: usize) -> Self {
+            RotatingDelayer { buf: vec![0u16; delay + 1] }
+        }
+
+        fn feed(&mut self, sample: u16) {
+            self.buf.rotate_right(1);
+            self.buf[0] = sample;
+        }
+
+        fn get_output(&self) -> u16 {
+            self.buf[self.buf.len() - 1]
+        }
+    }
+
+    fn random_sample(rng: &mut Rng) -> u16 {
+        (rng.uniform() * 65536.0) as u16
+    }
+
+    fn random_line(rng: &mut Rng) -> u128 {
+        (0..8).fold(0u128, |line, _| (line << 16) | random_sample(rng) as u128)
+    }
+
+    #[test]
+    fn ctl_flags_for_other_formats_are_refused() {
+        let mut control = ControlWord::new();
+        assert!(control.apply_flags("copyright, p,16bit").is_ok());
+        assert!(control.copyright);
+        assert!(control.to_line() == ControlWord { copyright: true, pre_emphasis: false }.to_line());
+        assert!(control.apply_flags("no-p").is_err());
+        assert!(control.apply_flags("14bit").is_err());
+    }
+
+    // cargo test --release -- --ignored --nocapture, on the Pi itself to mean anything
+    #[test]
+    #[ignore]
+    fn measure_crc() {
+        const LINES: usize = 1_000_000;
+        let mut rng = Rng::new(45);
+        let lines: Vec<u128> = (0..LINES).map(|_| random_line(&mut rng) >> 16).collect();
+
+        let start = Instant::now();
+        for line in &lines {
+            black_box(get_crc16_ccitt_false(black_box(*line), 112));
+        }
+
+        println!("CRC per line: {:.1} ns", start.elapsed().as_nanos() as f64 / LINES as f64);
+    }
+
+    #[test]
+    fn interleave_memory_matches_rotating_delayers() {
+        let mut rng = Rng::new(46);
+        let mut memory = InterleaveMemory::new();
+        let mut delayers: Vec<RotatingDelayer> = (0..7).map(|d| RotatingDelayer::new(d * INTERLEAVE_DELAY)).collect();
+
+        for line in 0..10 * INTERLEAVE_HISTORY {
+            for word in 0..7 {
+                let sample = random_sample(&mut rng);
+                memory.write(word, sample);
+                delayers[word].feed(sample);
+            }
+            for word in 0..7 {
+                assert_eq!(memory.read(word), delayers[word].get_output(), "word {} of line {}", word, line);
+            }
+            memory.next_line();
+        }
+    }
+
+    #[test]
+    fn engine_lines_decode_to_the_samples() {
+        let mut rng = Rng::new(1046);
+        let mut engine = PCMEngine::new();
+        let mut decoder = PCMDecoder::new();
+        let mut submitted: Vec<[u16; 6]> = vec![];
+        let mut decoded: Vec<[u16; 6]> = vec![];
+
+        for _ in 0..10 * INTERLEAVE_HISTORY {
+            let mut block = [0u16; 6];
+            for sample in block.iter_mut() {
+                *sample = random_sample(&mut rng);
+            }
+            submitted.push(block);
+
+            let mut line = None;
+            for stereo_sample in block.chunks(2) {
+                line = engine.submit_stereo_sample([stereo_sample[0], stereo_sample[1]]);
+            }
+            assert!(is_crc_valid(line.unwrap()));
+
+            if let Some(block) = decoder.submit_line(line) {
+                assert!(block.status.iter().all(|s| *s == SampleStatus::Valid));
+                decoded.push(block.samples);
+            }
+        }
+
+        // The decoder holds back a block until its last word has come through the interleave
+        assert_eq!(decoded.len(), submitted.len() - (INTERLEAVE_HISTORY - 1));
+        assert!(decoded.iter().zip(submitted.iter()).all(|(d, s)| d == s));
+    }
+
+    #[test]
+    fn missing_line_is_corrected_by_p() {
+        let mut rng = Rng::new(2046);
+        let mut engine = PCMEngine::new();
+        let mut decoder = PCMDecoder::new();
+        let mut submitted: Vec<[u16; 6]> = vec![];
+        let mut decoded: Vec<[u16; 6]> = vec![];
+
+        for index in 0..3 * INTERLEAVE_HISTORY {
+            let mut block = [0u16; 6];
+            for sample in block.iter_mut() {
+                *sample = random_sample(&mut rng);
+            }
+            submitted.push(block);
+
+            let mut line = None;
+            for stereo_sample in block.chunks(2) {
+                line = engine.submit_stereo_sample([stereo_sample[0], stereo_sample[1]]);
+            }
+            // A dropout takes a single line, its words belong to 7 different blocks
+            if index == INTERLEAVE_HISTORY { line = None; }
+
+            if let Some(block) = decoder.submit_line(line) {
+                decoded.push(block.samples);
+            }
+        }
+
+        assert_eq!(decoder.stats.crc_errors, 1);
+        assert_eq!(decoder.stats.lost_samples, 0);
+        assert_eq!(decoder.stats.corrected_samples, 6);
+        assert!(decoded.iter().zip(submitted.iter()).all(|(d, s)| d == s));
+    }
+}
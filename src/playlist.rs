@@ -1,10 +1,22 @@
+use crate::signal;
+use crate::error::{Error, Result};
+
 use std::fs::File;
 use std::io::{BufReader, prelude::*};
 use std::path::Path;
 
+const CTL_DIRECTIVE: &'static str = "#PICM-CTL:";
+
+#[derive(Clone)]
+pub struct PlaylistItem {
+    pub file: String,
+    /// CTL flags for this item only, from a #PICM-CTL: line before it
+    pub ctl_flags: Option<String>
+}
+
 pub struct Playlist {
     cwd: String,
-    files: Vec<String>,
+    files: Vec<PlaylistItem>,
     cursor: usize,
 }
 
@@ -14,35 +26,50 @@ impl Playlist {
 
         Playlist {
             cwd: if path.is_absolute() { String::from("") } else { String::from("./") },
-            files: vec![file],
+            files: vec![PlaylistItem { file: file, ctl_flags: None }],
             cursor: 0
         }
     }
 
-    pub fn new_from_m3u(m3u_file: String) -> Self {
+    pub fn new_from_m3u(m3u_file: String) -> Result<Self> {
         let path = Path::new(&m3u_file);
-        let cwd = String::from(path.parent().unwrap_or(Path::new(".")).to_str().unwrap()) + "/";
+        let cwd = String::from(path.parent().unwrap_or(Path::new(".")).to_string_lossy()) + "/";
 
-        let file = File::open(m3u_file).expect("Cannot open m3u file");
+        let file = File::open(&m3u_file).map_err(|e| Error::Input(format!("Cannot open playlist {}: {}", m3u_file, e)))?;
         let reader = BufReader::new(file);
 
-        let mut files: Vec<String> = vec![];
-        for (_, line) in reader.lines().enumerate() {
-            let line = line.unwrap();
-            if !line.starts_with("#") {
-                files.push(line);
+        let mut files: Vec<PlaylistItem> = vec![];
+        let mut ctl_flags: Option<String> = None;
+        for line in reader.lines() {
+            let line = line.map_err(|e| Error::Input(format!("Cannot read playlist {}: {}", m3u_file, e)))?;
+            if let Some(flags) = line.strip_prefix(CTL_DIRECTIVE) {
+                ctl_flags = Some(String::from(flags));
+            } else if !line.starts_with('#') {
+                files.push(PlaylistItem { file: line, ctl_flags: ctl_flags.take() });
             }
         }
 
-        Playlist {
+        if files.is_empty() {
+            return Err(Error::Input(format!("Playlist {} has no items", m3u_file)));
+        }
+
+        Ok(Playlist {
             cwd: cwd,
             files: files,
             cursor: 0
-        }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
     }
 
-    pub fn next_file(&mut self) -> String {
-        let result = self.cwd.clone() + &self.files[self.cursor].clone();
+    pub fn next_item(&mut self) -> PlaylistItem {
+        let item = &self.files[self.cursor];
+        // Generated signals are not files relative to the playlist, unless there is one by the name
+        let path = self.cwd.clone() + &item.file;
+        let file = if signal::is_signal_item(&item.file) && !Path::new(&path).exists() { item.file.clone() } else { path };
+        let result = PlaylistItem { file: file, ctl_flags: item.ctl_flags.clone() };
         self.cursor = if self.cursor == self.files.len() - 1 { 0 } else { self.cursor + 1 };
         result
     }
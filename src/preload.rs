@@ -0,0 +1,183 @@
+use crate::encoder;
+use crate::playlist::{Playlist, PlaylistItem};
+use crate::signal;
+use crate::error::{Error, Result};
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+const MEGABYTE: usize = 1024 * 1024;
+
+/// How much of the playlist is decoded into RAM ahead of playing it
+#[derive(Copy, Clone)]
+pub struct PreloadSettings {
+    /// Items decoded ahead of the one playing, None for the whole playlist
+    pub items: Option<usize>,
+    /// Bytes the decoded audio may take up altogether
+    pub budget: usize
+}
+
+impl PreloadSettings {
+    /// The number of items to decode ahead, or all, and the memory budget in megabytes
+    pub fn parse(spec: &str, budget_mb: usize) -> Option<PreloadSettings> {
+        let items = match spec {
+            "all" => None,
+            count => Some(count.parse::<usize>().ok().filter(|c| *c > 0)?)
+        };
+        Some(PreloadSettings { items: items, budget: budget_mb * MEGABYTE })
+    }
+}
+
+// Bytes of decoded audio held in RAM, given back as the items are played
+struct MemoryUse {
+    bytes: Mutex<usize>,
+    freed: Condvar
+}
+
+impl MemoryUse {
+    // Waits until the bytes fit in the budget next to what is held already
+    fn reserve(&self, bytes: usize, budget: usize) -> usize {
+        let mut used = self.bytes.lock().unwrap();
+        while *used + bytes > budget {
+            used = self.freed.wait(used).unwrap();
+        }
+        *used += bytes;
+        *used
+    }
+
+    fn release(&self, bytes: usize) {
+        *self.bytes.lock().unwrap() -= bytes;
+        self.freed.notify_all();
+    }
+}
+
+/// Decoded audio of a playlist item. Its memory goes back to the budget when dropped.
+pub struct PreloadedSamples {
+    samples: Vec<[u16; 2]>,
+    /// Reserved from the budget
+    bytes: usize,
+    memory: Arc<MemoryUse>
+}
+
+impl PreloadedSamples {
+    pub fn get(&self, position: usize) -> Option<[u16; 2]> {
+        self.samples.get(position).copied()
+    }
+}
+
+impl Drop for PreloadedSamples {
+    fn drop(&mut self) {
+        self.memory.release(self.bytes);
+    }
+}
+
+#[derive(Clone)]
+pub struct PreloadedItem {
+    pub item: PlaylistItem,
+    /// None for test signals, and for files not fitting in the budget even on their own,
+    /// these are opened when their turn comes like without preloading
+    pub samples: Option<Arc<PreloadedSamples>>
+}
+
+enum Items {
+    /// Decoded once before playing, handed out again and again as the playlist loops
+    Whole(Vec<PreloadedItem>, usize),
+    /// Decoded on a thread of its own, ahead of the one playing
+    Ahead(Receiver<PreloadedItem>)
+}
+
+/// Hands out the items of the playlist decoded into RAM, so the encoder doesn't read the
+/// storage while it takes a field
+pub struct Preloader {
+    items: Items,
+    playlist_len: usize
+}
+
+impl Preloader {
+    pub fn start(mut playlist: Playlist, settings: PreloadSettings) -> Self {
+        let memory = Arc::new(MemoryUse { bytes: Mutex::new(0), freed: Condvar::new() });
+        let playlist_len = playlist.len();
+        let budget_mb = settings.budget / MEGABYTE;
+
+        if settings.items.is_none() {
+            // Items which can't be opened are skipped when their turn comes
+            let needed: usize = (0..playlist.len()).map(|_| wave_bytes(&playlist.next_item()).unwrap_or(0)).sum();
+            if needed <= settings.budget {
+                println!("Preloading the whole playlist: {} items, {:.1} MB of the {} MB budget", playlist.len(), needed as f64 / MEGABYTE as f64, budget_mb);
+                let items = (0..playlist.len()).map(|_| preload_item(playlist.next_item(), &memory, settings.budget)).collect();
+                return Preloader { items: Items::Whole(items, 0), playlist_len: playlist_len };
+            }
+            println!("Warning: the playlist needs {:.1} MB, more than the preload budget of {} MB, preloading ahead as far as it goes",
+                needed as f64 / MEGABYTE as f64, budget_mb);
+        }
+
+        // The loader holds one item on top of the queued ones, waiting to hand it over
+        let (sender, receiver) = mpsc::sync_channel::<PreloadedItem>(settings.items.unwrap_or(playlist.len()) - 1);
+        match settings.items {
+            Some(items) => println!("Preloading {} item(s) ahead, {} MB budget", items, budget_mb),
+            None => println!("Preloading ahead, {} MB budget", budget_mb)
+        }
+
+        thread::spawn(move || {
+            loop {
+                let item = preload_item(playlist.next_item(), &memory, settings.budget);
+                if sender.send(item).is_err() { break; }
+            }
+        });
+
+        Preloader { items: Items::Ahead(receiver), playlist_len: playlist_len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.playlist_len
+    }
+
+    pub fn next_item(&mut self) -> Result<PreloadedItem> {
+        match &mut self.items {
+            Items::Whole(items, cursor) => {
+                let item = items[*cursor].clone();
+                *cursor = (*cursor + 1) % items.len();
+                Ok(item)
+            },
+            Items::Ahead(receiver) => receiver.recv().map_err(|_| Error::Input(String::from("The preloading of the playlist stopped")))
+        }
+    }
+}
+
+// Size of the decoded audio, 0 for what isn't preloaded
+fn wave_bytes(item: &PlaylistItem) -> std::result::Result<usize, hound::Error> {
+    if signal::is_signal_item(&item.file) {
+        return Ok(0);
+    }
+    Ok(hound::WavReader::open(&item.file)?.len() as usize * 2)
+}
+
+fn preload_item(item: PlaylistItem, memory: &Arc<MemoryUse>, budget: usize) -> PreloadedItem {
+    // What can't be opened is left to the encoder, to skip it with a warning when its turn comes
+    let bytes = wave_bytes(&item).unwrap_or(0);
+    if bytes == 0 {
+        return PreloadedItem { item: item, samples: None };
+    }
+    if bytes > budget {
+        println!("Warning: {} needs {:.1} MB, more than the preload budget, playing it from storage", item.file, bytes as f64 / MEGABYTE as f64);
+        return PreloadedItem { item: item, samples: None };
+    }
+    let mut wave = match encoder::open_wave(item.file.clone()) {
+        Ok(wave) => wave,
+        Err(_) => return PreloadedItem { item: item, samples: None }
+    };
+
+    let used = memory.reserve(bytes, budget);
+    let mut samples: Vec<[u16; 2]> = Vec::with_capacity(bytes / 4);
+    while let (Some(Ok(left)), Some(Ok(right))) = (wave.next(), wave.next()) {
+        samples.push([left as u16, right as u16]);
+    }
+    if samples.len() * 4 < bytes {
+        println!("Warning: cannot read {} any further, preloaded {:.1} s of it", item.file, samples.len() as f64 / 44100.0);
+    }
+    println!("Preloaded {}: {:.1} s, {:.1} MB, {:.1} MB in RAM", item.file, samples.len() as f64 / 44100.0,
+        bytes as f64 / MEGABYTE as f64, used as f64 / MEGABYTE as f64);
+
+    PreloadedItem { item: item, samples: Some(Arc::new(PreloadedSamples { samples: samples, bytes: bytes, memory: memory.clone() })) }
+}
@@ -0,0 +1,198 @@
+use std::{io, mem};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Policy {
+    Other,
+    Fifo,
+    RoundRobin
+}
+
+impl Policy {
+    fn to_libc(self) -> libc::c_int {
+        match self {
+            Policy::Other => libc::SCHED_OTHER,
+            Policy::Fifo => libc::SCHED_FIFO,
+            Policy::RoundRobin => libc::SCHED_RR
+        }
+    }
+}
+
+/// Scheduling policy of a thread, like fifo:80, rr:50, fifo (the highest priority) or other
+#[derive(Copy, Clone)]
+pub struct Scheduling {
+    policy: Policy,
+    /// None for the highest one of the policy
+    priority: Option<i32>
+}
+
+impl Scheduling {
+    pub fn parse(spec: &str) -> Option<Scheduling> {
+        let mut parts = spec.splitn(2, ':');
+        let policy = match parts.next()? {
+            "other" => Policy::Other,
+            "fifo" => Policy::Fifo,
+            "rr" => Policy::RoundRobin,
+            _ => return None
+        };
+        let priority = match parts.next() {
+            None | Some("max") => None,
+            Some(priority) => Some(priority.parse::<i32>().ok().filter(|p| (1..=99).contains(p))?)
+        };
+        // SCHED_OTHER has no priorities
+        if policy == Policy::Other && priority.is_some() { return None; }
+
+        Some(Scheduling { policy: policy, priority: priority })
+    }
+
+    fn priority(&self) -> i32 {
+        let policy = self.policy.to_libc();
+        self.priority.unwrap_or_else(|| unsafe { libc::sched_get_priority_max(policy) })
+    }
+}
+
+/// CPUs listed separated by commas, ranges allowed, like 2,3 or 1-3
+pub fn parse_cpus(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = vec![];
+    for item in list.split(',').map(|i| i.trim()) {
+        let mut range = item.splitn(2, '-');
+        let first = range.next()?.parse::<usize>().ok()?;
+        let last = match range.next() {
+            Some(last) => last.parse::<usize>().ok()?,
+            None => first
+        };
+        if first > last || last >= libc::CPU_SETSIZE as usize { return None; }
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+/// What a thread asks for, left alone when not given
+#[derive(Clone, Default)]
+pub struct ThreadSettings {
+    pub scheduling: Option<Scheduling>,
+    pub cpus: Option<Vec<usize>>
+}
+
+impl ThreadSettings {
+    /// What the calling thread runs with, for threads to go back to instead of taking after
+    /// the thread starting them
+    pub fn of_current_thread() -> Self {
+        let mut policy = 0;
+        let mut param = libc::sched_param { sched_priority: 0 };
+        unsafe { libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param); }
+
+        let scheduling = match policy {
+            libc::SCHED_FIFO => Some(Scheduling { policy: Policy::Fifo, priority: Some(param.sched_priority) }),
+            libc::SCHED_RR => Some(Scheduling { policy: Policy::RoundRobin, priority: Some(param.sched_priority) }),
+            libc::SCHED_OTHER => Some(Scheduling { policy: Policy::Other, priority: None }),
+            _ => None
+        };
+        let cpus = Some(get_affinity()).filter(|cpus| !cpus.is_empty());
+        ThreadSettings { scheduling: scheduling, cpus: cpus }
+    }
+
+    /// These settings, with what they leave alone taken from the defaults
+    pub fn or(&self, defaults: &ThreadSettings) -> Self {
+        ThreadSettings {
+            scheduling: self.scheduling.or(defaults.scheduling),
+            cpus: self.cpus.clone().or_else(|| defaults.cpus.clone())
+        }
+    }
+
+    /// Applies the settings to the calling thread. What can't be granted is only warned about,
+    /// picm plays on without it, the report tells what the thread ended up with.
+    pub fn apply(&self, thread_name: &str) {
+        if let Some(scheduling) = &self.scheduling {
+            if let Err(error) = set_scheduling(scheduling) {
+                println!("Warning: cannot set the scheduling of the {} thread: {}", thread_name, error);
+            }
+        }
+        if let Some(cpus) = &self.cpus {
+            if let Err(error) = set_affinity(cpus) {
+                println!("Warning: cannot pin the {} thread to CPUs {}: {}", thread_name, format_cpus(cpus), error);
+            }
+        }
+    }
+}
+
+fn set_scheduling(scheduling: &Scheduling) -> io::Result<()> {
+    let param = libc::sched_param { sched_priority: scheduling.priority() };
+    match unsafe { libc::pthread_setschedparam(libc::pthread_self(), scheduling.policy.to_libc(), &param) } {
+        0 => Ok(()),
+        error => Err(io::Error::from_raw_os_error(error))
+    }
+}
+
+fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        // 0 is the calling thread
+        if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn get_affinity() -> Vec<usize> {
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return vec![];
+        }
+        (0..libc::CPU_SETSIZE as usize).filter(|cpu| libc::CPU_ISSET(*cpu, &set)).collect()
+    }
+}
+
+fn format_cpus(cpus: &[usize]) -> String {
+    cpus.iter().map(|cpu| cpu.to_string()).collect::<Vec<String>>().join(",")
+}
+
+/// Prints the scheduling the calling thread actually runs with
+pub fn report(thread_name: &str) {
+    let mut policy = 0;
+    let mut param = libc::sched_param { sched_priority: 0 };
+    unsafe { libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param); }
+
+    let scheduling = match policy {
+        libc::SCHED_FIFO => format!("SCHED_FIFO priority {}", param.sched_priority),
+        libc::SCHED_RR => format!("SCHED_RR priority {}", param.sched_priority),
+        libc::SCHED_OTHER => format!("SCHED_OTHER nice {}", unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }),
+        _ => format!("policy {} priority {}", policy, param.sched_priority)
+    };
+    println!("Thread {}: {}, CPUs {}", thread_name, scheduling, format_cpus(&get_affinity()));
+}
+
+/// Locks the memory of picm, and everything it maps later, into RAM so the draw thread never
+/// waits for a page to be brought back in
+pub fn lock_memory() {
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        println!("Warning: cannot lock the memory: {}", io::Error::last_os_error());
+    } else {
+        println!("Memory: locked");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_not_given_come_from_the_defaults() {
+        let defaults = ThreadSettings { scheduling: Scheduling::parse("other"), cpus: Some(vec![0, 1, 2, 3]) };
+        let given = ThreadSettings { scheduling: Scheduling::parse("fifo:80"), cpus: None };
+
+        let settings = given.or(&defaults);
+        let scheduling = settings.scheduling.unwrap();
+        assert!(scheduling.policy == Policy::Fifo && scheduling.priority == Some(80));
+        assert_eq!(settings.cpus, Some(vec![0, 1, 2, 3]));
+
+        let settings = ThreadSettings::default().or(&defaults);
+        assert!(settings.scheduling.unwrap().policy == Policy::Other);
+        let given = ThreadSettings { scheduling: None, cpus: parse_cpus("2,3") };
+        assert_eq!(given.or(&defaults).cpus, Some(vec![2, 3]));
+    }
+}
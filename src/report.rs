@@ -0,0 +1,125 @@
+use crate::PCMMode;
+use crate::vsync;
+use crate::telemetry::{FieldLog, FieldTimes};
+use crate::timer::RenderTimer;
+use crate::metrics::SharedMetrics;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+// Seconds of fields the reporting thread may fall behind before events are dropped
+const QUEUE_SECONDS: usize = 4;
+
+/// Something the draw thread has to tell
+pub enum Event {
+    /// Silence was filled in, and the fields came again
+    Underrun { silent_fields: u64, underruns: u64 },
+    DoubleWakeup { double_wakeups: u64 },
+    MissedVsyncs { missed: u64, missed_vsyncs: u64 },
+    /// A field was handed over to the display
+    Field { sequence: Option<u64>, times: FieldTimes, late_submits: u64, fields: u64 },
+    Drift { field_rate: f64, sample_rate: f64, ppm: f64, corrected: bool },
+    /// The draw loop ended
+    Stopped { fields: u64, late_submits: u64, missed_vsyncs: u64, double_wakeups: u64 }
+}
+
+/// Hands the events of the draw thread over to a thread of its own, which formats, prints
+/// and logs them. Printing takes the stdout lock and allocates, and blocks on a slow pipe
+/// or terminal, which the draw thread can't afford. Nothing waits: when the reporting
+/// thread is that far behind, the events are dropped and counted.
+#[derive(Clone)]
+pub struct Reporter {
+    sender: SyncSender<Event>,
+    dropped: Arc<AtomicU64>
+}
+
+impl Reporter {
+    pub fn send(&self, event: Event) {
+        if self.sender.try_send(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The last event, waits for room as the draw loop is over
+    pub fn finish(self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+}
+
+/// Starts the reporting thread, which ends once every Reporter is dropped. The render
+/// times and the field log are kept there too.
+pub fn spawn(mode: &PCMMode, log: Option<FieldLog>, timer: Option<RenderTimer>, metrics: SharedMetrics) -> (Reporter, JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(mode.field_rate as usize * QUEUE_SECONDS);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let (rate_num, rate_den) = vsync::get_exact_field_rate(mode);
+    let period_ns = 1_000_000_000 * rate_den as u128 / rate_num as u128;
+
+    let thread_dropped = dropped.clone();
+    let handle = thread::spawn(move || report(receiver, thread_dropped, period_ns, log, timer, metrics));
+    (Reporter { sender: sender, dropped: dropped }, handle)
+}
+
+fn report(receiver: Receiver<Event>, dropped: Arc<AtomicU64>, period_ns: u128, mut log: Option<FieldLog>, mut timer: Option<RenderTimer>, metrics: SharedMetrics) {
+    let mut dropped_reported = 0;
+
+    for event in receiver {
+        match event {
+            Event::Underrun { silent_fields, underruns } => {
+                println!("Underrun: {} fields of silence, {} underruns so far", silent_fields, underruns);
+            },
+            Event::DoubleWakeup { double_wakeups } => {
+                println!("Woke up twice within a field, repeating it ({} double wakeups so far)", double_wakeups);
+            },
+            Event::MissedVsyncs { missed, missed_vsyncs } => {
+                println!("Missed {} vsync(s), dropping as many fields ({} missed so far)", missed, missed_vsyncs);
+            },
+            Event::Field { sequence, times, late_submits, fields } => {
+                let late_ns = times.late_ns(period_ns);
+                if let Some(late_ns) = late_ns {
+                    println!("Late submit: field {} handed over {:.1} ms after the next vsync, rendering took {:.1} ms ({} late of {} fields)",
+                        format_sequence(sequence), late_ns as f64 / 1e6,
+                        (times.render_end_ns - times.render_start_ns) as f64 / 1e6, late_submits, fields);
+                }
+
+                if let Some(timer) = &mut timer {
+                    if let Some(summary) = timer.add(((times.submit_ns - times.render_start_ns) / 1000) as u64) {
+                        metrics.lock().unwrap().render_times = Some(summary);
+                    }
+                }
+
+                if let Some(field_log) = &mut log {
+                    // Not worth stopping the recording for
+                    if let Err(error) = field_log.write(sequence, &times, late_ns.is_some()) {
+                        println!("Warning: cannot write the field log, no more fields are logged: {}", error);
+                        log = None;
+                    }
+                }
+            },
+            Event::Drift { field_rate, sample_rate, ppm, corrected } => {
+                println!("Field clock: {:.4} Hz, audio plays at {:.1} Hz, drift {:+.0} ppm{}", field_rate, sample_rate, ppm,
+                    if corrected { " (corrected)" } else { "" });
+            },
+            Event::Stopped { fields, late_submits, missed_vsyncs, double_wakeups } => {
+                if let Some(timer) = &timer { timer.print_total(); }
+                println!("Fields: {} shown, {} late submits, {} missed vsyncs, {} double wakeups",
+                    fields, late_submits, missed_vsyncs, double_wakeups);
+            }
+        }
+
+        let dropped = dropped.load(Ordering::Relaxed);
+        if dropped > dropped_reported {
+            println!("Warning: the reporting fell behind, {} events of the draw thread were dropped", dropped);
+            dropped_reported = dropped;
+        }
+    }
+}
+
+// Silence filled in has no sequence number
+fn format_sequence(sequence: Option<u64>) -> String {
+    match sequence {
+        Some(sequence) => sequence.to_string(),
+        None => String::from("silence")
+    }
+}
@@ -0,0 +1,55 @@
+use crate::vsync::RateCorrection;
+
+use std::collections::VecDeque;
+
+// 4 point, 3rd order Hermite interpolation
+fn hermite(x: [f64; 4], t: f64) -> f64 {
+    let c0 = x[1];
+    let c1 = 0.5 * (x[2] - x[0]);
+    let c2 = x[0] - 2.5 * x[1] + 2.0 * x[2] - 0.5 * x[3];
+    let c3 = 0.5 * (x[3] - x[0]) + 1.5 * (x[1] - x[2]);
+    ((c3 * t + c2) * t + c1) * t + c0
+}
+
+fn to_sample(value: f64) -> u16 {
+    value.round().max(i16::MIN as f64).min(i16::MAX as f64) as i16 as u16
+}
+
+/// Changes the speed of the audio slightly, by the ratio the field clock drift asks for.
+pub struct Resampler {
+    correction: RateCorrection,
+    history: [[f64; 4]; 2],
+    position: f64,
+    output: VecDeque<[u16; 2]>
+}
+
+impl Resampler {
+    pub fn new(correction: RateCorrection) -> Self {
+        Resampler {
+            correction: correction,
+            history: [[0.0; 4]; 2],
+            position: 0.0,
+            output: VecDeque::with_capacity(4)
+        }
+    }
+
+    pub fn push(&mut self, stereo_sample: [u16; 2]) {
+        for c in 0..2 {
+            self.history[c].rotate_left(1);
+            self.history[c][3] = stereo_sample[c] as i16 as f64;
+        }
+
+        // Input samples to advance for every output sample
+        let step = 1.0 / self.correction.get_ratio();
+        while self.position < 1.0 {
+            let t = self.position;
+            self.output.push_back([to_sample(hermite(self.history[0], t)), to_sample(hermite(self.history[1], t))]);
+            self.position += step;
+        }
+        self.position -= 1.0;
+    }
+
+    pub fn pop(&mut self) -> Option<[u16; 2]> {
+        self.output.pop_front()
+    }
+}
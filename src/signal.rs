@@ -0,0 +1,150 @@
+use crate::simulator::Rng;
+
+use std::f64::consts::PI;
+use std::path::Path;
+
+const SAMPLE_RATE: f64 = 44100.0;
+const FULL_SCALE: f64 = 32767.0;
+const DEFAULT_LEVEL_DBFS: f64 = -20.0;
+const DEFAULT_SECONDS: f64 = 30.0;
+const REFERENCE_FREQUENCY: f64 = 1000.0;
+
+#[derive(Copy, Clone)]
+enum Signal {
+    Tone(f64),
+    Sweep(f64, f64),
+    WhiteNoise,
+    PinkNoise,
+    Silence,
+    /// The tone in the left channel for a second, then in the right one
+    Ident
+}
+
+const KINDS: &'static [&'static str] = &["tone", "sweep", "white", "pink", "silence", "ident", "align"];
+
+/// Marks a playlist item as a generated signal, e.g. signal:tone:1000
+const SIGNAL_PREFIX: &'static str = "signal:";
+
+/// Whether a playlist item names a generated signal instead of a file. A file which is
+/// there by the name is played as a file.
+pub fn is_signal_item(item: &str) -> bool {
+    item.starts_with(SIGNAL_PREFIX) && !Path::new(item).exists()
+}
+
+/// Synthetic test signal standing in for a WAV file in the playlist. Items are signal:,
+/// the kind and its arguments, separated by colons:
+///
+/// * tone:<Hz>[:<dBFS>[:<seconds>]]
+/// * sweep:<from Hz>:<to Hz>[:<dBFS>[:<seconds>]], logarithmic
+/// * white[:<dBFS>[:<seconds>]] and pink[:<dBFS>[:<seconds>]], RMS level
+/// * silence[:<seconds>]
+/// * ident[:<dBFS>[:<seconds>]], 1 kHz alternating between left and right every second
+/// * align[:<seconds>], 1 kHz at -20 dBFS
+///
+/// Levels default to -20 dBFS, lengths to 30 seconds, empty arguments take the default.
+pub struct SignalGenerator {
+    signal: Signal,
+    amplitude: f64,
+    length: u64,
+    position: u64,
+    phase: f64,
+    rng: Rng,
+    pink: [f64; 7]
+}
+
+impl SignalGenerator {
+    /// Fails with a message telling what is wrong with the item
+    pub fn new(item: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = item.strip_prefix(SIGNAL_PREFIX).unwrap_or(item).split(':').collect();
+        let number = |index: usize, default: Option<f64>| -> Result<f64, String> {
+            match fields.get(index).filter(|field| !field.is_empty()) {
+                Some(field) => field.parse::<f64>().map_err(|_| format!("Invalid number {} in test signal {}", field, item)),
+                None => default.ok_or_else(|| format!("Missing argument {} of test signal {}", index, item))
+            }
+        };
+
+        let (signal, level, seconds) = match fields[0] {
+            "tone" => (Signal::Tone(number(1, None)?), number(2, Some(DEFAULT_LEVEL_DBFS))?, number(3, Some(DEFAULT_SECONDS))?),
+            "sweep" => (Signal::Sweep(number(1, None)?, number(2, None)?), number(3, Some(DEFAULT_LEVEL_DBFS))?, number(4, Some(DEFAULT_SECONDS))?),
+            "white" => (Signal::WhiteNoise, number(1, Some(DEFAULT_LEVEL_DBFS))?, number(2, Some(DEFAULT_SECONDS))?),
+            "pink" => (Signal::PinkNoise, number(1, Some(DEFAULT_LEVEL_DBFS))?, number(2, Some(DEFAULT_SECONDS))?),
+            "silence" => (Signal::Silence, 0.0, number(1, Some(DEFAULT_SECONDS))?),
+            "ident" => (Signal::Ident, number(1, Some(DEFAULT_LEVEL_DBFS))?, number(2, Some(DEFAULT_SECONDS))?),
+            "align" => (Signal::Tone(REFERENCE_FREQUENCY), DEFAULT_LEVEL_DBFS, number(1, Some(DEFAULT_SECONDS))?),
+            _ => return Err(format!("Unknown test signal: {}, available: {}", item, KINDS.join(", ")))
+        };
+
+        if let Signal::Sweep(from, to) = signal {
+            if from <= 0.0 || to <= 0.0 { return Err(format!("Sweep frequencies need to be positive: {}", item)); }
+        }
+        if level > 0.0 { return Err(format!("Level can't be above 0 dBFS: {}", item)); }
+        if seconds <= 0.0 { return Err(format!("Length needs to be positive: {}", item)); }
+
+        Ok(SignalGenerator {
+            signal: signal,
+            amplitude: FULL_SCALE * 10f64.powf(level / 20.0),
+            length: (seconds * SAMPLE_RATE) as u64,
+            position: 0,
+            phase: 0.0,
+            rng: Rng::new(0x0015_E0F_5EED),
+            pink: [0.0; 7]
+        })
+    }
+
+    fn next_sine(&mut self, frequency: f64) -> f64 {
+        let value = self.phase.sin();
+        self.phase = (self.phase + 2.0 * PI * frequency / SAMPLE_RATE) % (2.0 * PI);
+        value
+    }
+
+    // Paul Kellett's refined filter, scaled to the RMS level of the white noise
+    fn next_pink(&mut self) -> f64 {
+        let white = self.rng.gaussian() as f64;
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        pink * 0.328
+    }
+
+    /// Number of stereo samples the signal lasts
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn next_stereo_samples(&mut self) -> Option<[u16; 2]> {
+        if self.position == self.length { return None; }
+
+        let t = self.position as f64 / SAMPLE_RATE;
+        let (left, right) = match self.signal {
+            Signal::Tone(frequency) => {
+                let value = self.next_sine(frequency);
+                (value, value)
+            },
+            Signal::Sweep(from, to) => {
+                let frequency = from * (to / from).powf(self.position as f64 / self.length as f64);
+                let value = self.next_sine(frequency);
+                (value, value)
+            },
+            Signal::WhiteNoise => (self.rng.gaussian() as f64, self.rng.gaussian() as f64),
+            Signal::PinkNoise => {
+                let value = self.next_pink();
+                (value, value)
+            },
+            Signal::Silence => (0.0, 0.0),
+            Signal::Ident => {
+                let value = self.next_sine(REFERENCE_FREQUENCY);
+                if (t as u64) % 2 == 0 { (value, 0.0) } else { (0.0, value) }
+            }
+        };
+        self.position += 1;
+
+        let to_sample = |value: f64| (value * self.amplitude).round().clamp(-FULL_SCALE - 1.0, FULL_SCALE) as i16 as u16;
+        Some([to_sample(left), to_sample(right)])
+    }
+}
@@ -0,0 +1,284 @@
+use crate::{PCMMode, PCM_DATA_WIDTH};
+use crate::geometry::Geometry;
+use crate::levels::Levels;
+use crate::encoder::LineSource;
+use crate::error::Result;
+use crate::pcm::{self, PCMDecoder, SampleStatus};
+
+use std::f32::consts::PI;
+
+#[derive(Copy, Clone)]
+pub struct ImpairmentProfile {
+    pub name: &'static str,
+    /// Probability of a dropout starting on a line
+    pub dropout_rate: f32,
+    /// Maximum dropout length in physical pixels
+    pub dropout_length: i32,
+    /// Standard deviation of the per line timebase error in physical pixels
+    pub jitter: f32,
+    /// Probability of a field showing the previous field from a random line on
+    pub tear_rate: f32,
+    /// Standard deviation of the additive noise relative to the white level
+    pub noise: f32,
+    /// Amplitude of the slow gain variation
+    pub level_drift: f32,
+    /// Lines lost at the bottom of every field
+    pub missing_lines: i32,
+    /// Lines skewed by the head switching point before the end of the field
+    pub head_switch_lines: i32
+}
+
+const PROFILES: &'static [ImpairmentProfile] = &[
+    ImpairmentProfile { name: "clean", dropout_rate: 0.0, dropout_length: 0, jitter: 0.0, tear_rate: 0.0, noise: 0.0, level_drift: 0.0, missing_lines: 0, head_switch_lines: 0 },
+    ImpairmentProfile { name: "beta", dropout_rate: 0.0005, dropout_length: 60, jitter: 0.3, tear_rate: 0.0, noise: 0.04, level_drift: 0.03, missing_lines: 0, head_switch_lines: 3 },
+    ImpairmentProfile { name: "vhs", dropout_rate: 0.001, dropout_length: 120, jitter: 0.6, tear_rate: 0.0, noise: 0.07, level_drift: 0.05, missing_lines: 0, head_switch_lines: 5 },
+    ImpairmentProfile { name: "vhs-worn", dropout_rate: 0.005, dropout_length: 300, jitter: 0.9, tear_rate: 0.0, noise: 0.09, level_drift: 0.1, missing_lines: 2, head_switch_lines: 7 },
+    ImpairmentProfile { name: "tearing", dropout_rate: 0.0, dropout_length: 0, jitter: 0.0, tear_rate: 0.02, noise: 0.0, level_drift: 0.0, missing_lines: 0, head_switch_lines: 0 }
+];
+
+impl ImpairmentProfile {
+    pub fn from_name(name: &str) -> Option<Self> {
+        PROFILES.iter().find(|p| p.name == name).copied()
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        PROFILES.iter().map(|p| p.name).collect()
+    }
+}
+
+// xorshift64*, we only need repeatable noise, not quality randomness
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed | 1 }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    pub fn uniform(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn gaussian(&mut self) -> f32 {
+        let u1 = self.uniform().max(1e-7);
+        let u2 = self.uniform();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+}
+
+fn sample_at(line: &[f32], x: f32) -> f32 {
+    if x < 0.0 || x >= (line.len() - 1) as f32 { return 0.0; }
+    let i = x.floor() as usize;
+    let f = x - i as f32;
+    line[i] * (1.0 - f) + line[i + 1] * f
+}
+
+fn shift_line(line: &mut [f32], shift: f32) {
+    let source = line.to_vec();
+    for (x, value) in line.iter_mut().enumerate() {
+        *value = sample_at(&source, x as f32 - shift);
+    }
+}
+
+pub struct SimulationReport {
+    pub fields: u64,
+    pub stats: pcm::DecoderStats,
+    pub mismatched_samples: u64
+}
+
+impl SimulationReport {
+    pub fn print(&self, profile: &ImpairmentProfile) {
+        let stats = &self.stats;
+        let percent = |x: u64, of: u64| if of > 0 { x as f64 * 100.0 / of as f64 } else { 0.0 };
+        println!("Impairment profile: {}", profile.name);
+        println!("Fields: {}", self.fields);
+        println!("Lines: {}, CRC errors: {} ({:.3}%)", stats.lines, stats.crc_errors, percent(stats.crc_errors, stats.lines));
+        println!("Samples: {}", stats.samples);
+        println!("  corrected by P: {} ({:.3}%)", stats.corrected_samples, percent(stats.corrected_samples, stats.samples));
+        println!("  lost: {} ({:.3}%)", stats.lost_samples, percent(stats.lost_samples, stats.samples));
+        println!("  undetected errors: {} ({:.3}%)", self.mismatched_samples, percent(self.mismatched_samples, stats.samples));
+    }
+}
+
+/// Renders encoded fields as analog lines, runs them through the impairments of the
+/// profile, then slices and decodes them the way a PCM processor would.
+pub struct Simulator {
+    mode: PCMMode,
+    geometry: Geometry,
+    profile: ImpairmentProfile,
+    rng: Rng,
+    levels: [f32; 3],
+    physical_pixel_width: f32,
+    previous_field: Vec<Vec<f32>>,
+    field_index: u64,
+    decoder: PCMDecoder,
+    reference_decoder: PCMDecoder,
+    mismatched_samples: u64
+}
+
+impl Simulator {
+    pub fn new(mode: PCMMode, geometry: Geometry, levels: Levels, profile: ImpairmentProfile) -> Self {
+        Simulator {
+            mode: mode,
+            physical_pixel_width: geometry.physical_pixel_width(&mode),
+            geometry: geometry,
+            profile: profile,
+            rng: Rng::new(0x5EED_0FF1_E1D5),
+            levels: levels.get_lumas(),
+            previous_field: vec![],
+            field_index: 0,
+            decoder: PCMDecoder::new(),
+            reference_decoder: PCMDecoder::new(),
+            mismatched_samples: 0
+        }
+    }
+
+    fn render_line(&self, line_data: u128) -> Vec<f32> {
+        let pixel_bytes = self.geometry.get_line_pixel_bytes(line_data);
+        let left_offset = self.geometry.left_offset;
+
+        let mut line = vec![self.levels[0]; self.mode.screen_width as usize];
+        for x in left_offset..self.mode.screen_width {
+            let pixel = ((x - left_offset) as f32 / self.physical_pixel_width) as usize;
+            line[x as usize] = self.levels[pixel_bytes[pixel.min(pixel_bytes.len() - 1)] as usize];
+        }
+        line
+    }
+
+    fn impair_field(&mut self, field: &mut [Vec<f32>]) {
+        let profile = self.profile;
+        let height = field.len() as i32;
+
+        if profile.tear_rate > 0.0 && self.previous_field.len() == field.len() && self.rng.uniform() < profile.tear_rate {
+            let tear_line = (self.rng.uniform() * height as f32) as usize;
+            field[tear_line..].clone_from_slice(&self.previous_field[tear_line..]);
+        }
+        self.previous_field = field.to_vec();
+
+        let gain = 1.0 + profile.level_drift * (self.field_index as f32 * 2.0 * PI / 250.0).sin();
+
+        for (y, line) in field.iter_mut().enumerate() {
+            let y = y as i32;
+
+            if y >= height - profile.missing_lines {
+                for level in line.iter_mut() { *level = self.levels[0]; }
+                continue;
+            }
+
+            let mut shift = profile.jitter * self.rng.gaussian();
+            let head_switch_start = height - profile.missing_lines - profile.head_switch_lines;
+            if y >= head_switch_start {
+                // Skew grows towards the switching point
+                shift += (y - head_switch_start + 1) as f32 * self.physical_pixel_width * 0.75;
+            }
+            if shift != 0.0 { shift_line(line, shift); }
+
+            if profile.dropout_rate > 0.0 && self.rng.uniform() < profile.dropout_rate {
+                let length = 1 + (self.rng.uniform() * profile.dropout_length as f32) as usize;
+                let start = (self.rng.uniform() * line.len() as f32) as usize;
+                for x in start..(start + length).min(line.len()) {
+                    line[x] = self.levels[0];
+                }
+            }
+
+            for level in line.iter_mut() {
+                *level = *level * gain + profile.noise * self.rng.gaussian();
+            }
+        }
+    }
+
+    fn slice_line(&self, line: &[f32]) -> u128 {
+        let nominal_white = self.levels[2];
+        let nominal_threshold = self.levels[1] / 2.0;
+
+        let geometry = &self.geometry;
+        let cell_center = |start: f32, cell: i32| start + cell as f32 * self.physical_pixel_width + self.physical_pixel_width / 2.0;
+
+        // Track the level through the white cells of the reference at the end of the line
+        let mut white_reference = 0.0;
+        let mut white_cells = 0;
+        for (p, color) in geometry.white_reference.iter().enumerate() {
+            if *color != 2 { continue; }
+            white_reference += sample_at(line, cell_center(geometry.left_offset as f32, geometry.white_reference_start() + p as i32));
+            white_cells += 1;
+        }
+        let threshold = if white_cells > 0 && white_reference / white_cells as f32 > nominal_white / 2.0 {
+            nominal_threshold * white_reference / white_cells as f32 / nominal_white
+        } else {
+            nominal_threshold
+        };
+
+        // Lock to the first rising edge of the preamble
+        let edge_cell = geometry.preamble.iter().position(|color| *color != 0).unwrap_or(0) as i32;
+        let nominal_edge = geometry.left_offset + (edge_cell as f32 * self.physical_pixel_width) as i32;
+        let search_width = (self.physical_pixel_width * 3.0) as i32;
+        let mut start = geometry.left_offset as f32;
+        for x in (nominal_edge - search_width).max(1)..(nominal_edge + search_width).min(line.len() as i32) {
+            let (previous, current) = (line[x as usize - 1], line[x as usize]);
+            if previous < threshold && current >= threshold {
+                start = x as f32 - 1.0 + (threshold - previous) / (current - previous) - edge_cell as f32 * self.physical_pixel_width;
+                break;
+            }
+        }
+
+        let mut data = 0u128;
+        for b in 0..PCM_DATA_WIDTH {
+            let x = cell_center(start, geometry.preamble_width() + b);
+            if sample_at(line, x) >= threshold {
+                data |= 1 << (PCM_DATA_WIDTH - 1 - b);
+            }
+        }
+        data
+    }
+
+    fn decode(&mut self, clean_line: Option<u128>, line: Option<u128>) {
+        let reference = self.reference_decoder.submit_line(clean_line);
+        let decoded = self.decoder.submit_line(line);
+
+        if let (Some(reference), Some(decoded)) = (reference, decoded) {
+            for s in 0..6 {
+                if decoded.status[s] != SampleStatus::Lost && decoded.samples[s] != reference.samples[s] {
+                    self.mismatched_samples += 1;
+                }
+            }
+        }
+    }
+
+    pub fn run_field(&mut self, source: &mut dyn LineSource) -> Result<()> {
+        let field_lines = source.next_field_lines(&self.mode)?;
+        let clean_lines = &field_lines[1..];
+
+        let mut field: Vec<Vec<f32>> = field_lines.iter().map(|line_data| self.render_line(*line_data)).collect();
+
+        self.impair_field(&mut field);
+
+        for current_line in 0..self.mode.pcm_data_lines_in_field as usize {
+            if current_line < clean_lines.len() {
+                let sliced = self.slice_line(&field[current_line + 1]);
+                self.decode(Some(clean_lines[current_line]), Some(sliced));
+            } else {
+                // Lines beyond the visible area never make it to the picture
+                self.decode(None, None);
+            }
+        }
+
+        self.field_index += 1;
+        Ok(())
+    }
+
+    pub fn report(&self) -> SimulationReport {
+        SimulationReport {
+            fields: self.field_index,
+            stats: self.decoder.stats,
+            mismatched_samples: self.mismatched_samples
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::PCMMode;
+use crate::vsync::{self, get_monotonic_ns};
+use crate::report::{Event, Reporter};
+use crate::error::{Error, Result};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// When the things happened to a field, in nanoseconds of the monotonic clock
+#[derive(Copy, Clone, Default)]
+pub struct FieldTimes {
+    pub vsync_ns: u128,
+    pub wakeup_ns: u128,
+    pub render_start_ns: u128,
+    pub render_end_ns: u128,
+    pub submit_ns: u128
+}
+
+impl FieldTimes {
+    /// How long after the next vsync the field was handed over, None when it was in time
+    pub fn late_ns(&self, period_ns: u128) -> Option<u128> {
+        let next_vsync_ns = self.vsync_ns + period_ns;
+        if self.submit_ns > next_vsync_ns { Some(self.submit_ns - next_vsync_ns) } else { None }
+    }
+}
+
+/// CSV file with the times of every field
+pub struct FieldLog {
+    log: BufWriter<File>
+}
+
+impl FieldLog {
+    pub fn create(file: &String) -> Result<Self> {
+        let create = || -> io::Result<BufWriter<File>> {
+            let mut log = BufWriter::new(File::create(file)?);
+            writeln!(log, "sequence,vsync_ns,wakeup_ns,render_start_ns,render_end_ns,submit_ns,late")?;
+            Ok(log)
+        };
+        Ok(FieldLog { log: create().map_err(|e| Error::Output(format!("Cannot create field log {}: {}", file, e)))? })
+    }
+
+    pub fn write(&mut self, sequence: Option<u64>, times: &FieldTimes, late: bool) -> io::Result<()> {
+        let log = &mut self.log;
+        match sequence {
+            Some(sequence) => write!(log, "{}", sequence),
+            None => write!(log, "silence")
+        }?;
+        writeln!(log, ",{},{},{},{},{},{}", times.vsync_ns, times.wakeup_ns,
+            times.render_start_ns, times.render_end_ns, times.submit_ns, late as u8)
+    }
+}
+
+/// Stamps every field on its way to the screen, to see the problems of the field clock
+/// instead of guessing: late submits, where the field was not handed over before the next
+/// vsync and the previous one stayed on screen, double wakeups and missed vsyncs. These
+/// are counted, and reported along with the times of every field for the field log.
+pub struct FieldTelemetry {
+    period_ns: u128,
+    times: FieldTimes,
+    fields: u64,
+    late_submits: u64,
+    double_wakeups: u64,
+    missed_vsyncs: u64,
+    reporter: Reporter
+}
+
+impl FieldTelemetry {
+    pub fn new(mode: &PCMMode, reporter: Reporter) -> Self {
+        let (rate_num, rate_den) = vsync::get_exact_field_rate(mode);
+
+        FieldTelemetry {
+            period_ns: 1_000_000_000 * rate_den as u128 / rate_num as u128,
+            times: FieldTimes::default(),
+            fields: 0,
+            late_submits: 0,
+            double_wakeups: 0,
+            missed_vsyncs: 0,
+            reporter: reporter
+        }
+    }
+
+    /// The draw thread woke up for the vsync at the given time, with the fields of the clock
+    /// passed since the previous wakeup
+    pub fn wakeup(&mut self, vsync_ns: u128, clock_fields: u64) {
+        self.times = FieldTimes { vsync_ns: vsync_ns, wakeup_ns: get_monotonic_ns(), ..FieldTimes::default() };
+
+        if clock_fields == 0 {
+            self.double_wakeups += 1;
+            self.reporter.send(Event::DoubleWakeup { double_wakeups: self.double_wakeups });
+        } else if clock_fields > 1 {
+            self.missed_vsyncs += clock_fields - 1;
+            self.reporter.send(Event::MissedVsyncs { missed: clock_fields - 1, missed_vsyncs: self.missed_vsyncs });
+        }
+    }
+
+    pub fn render_start(&mut self) {
+        self.times.render_start_ns = get_monotonic_ns();
+    }
+
+    pub fn render_end(&mut self) {
+        self.times.render_end_ns = get_monotonic_ns();
+    }
+
+    /// The field was handed over to the display
+    pub fn submitted(&mut self, sequence: Option<u64>) {
+        self.times.submit_ns = get_monotonic_ns();
+        self.fields += 1;
+        if self.times.late_ns(self.period_ns).is_some() {
+            self.late_submits += 1;
+        }
+        self.reporter.send(Event::Field { sequence: sequence, times: self.times, late_submits: self.late_submits, fields: self.fields });
+    }
+
+    pub fn late_submits(&self) -> u64 {
+        self.late_submits
+    }
+
+    pub fn double_wakeups(&self) -> u64 {
+        self.double_wakeups
+    }
+
+    pub fn missed_vsyncs(&self) -> u64 {
+        self.missed_vsyncs
+    }
+
+    /// Hands the totals over once the draw loop ended
+    pub fn finish(self) {
+        self.reporter.finish(Event::Stopped { fields: self.fields, late_submits: self.late_submits,
+            missed_vsyncs: self.missed_vsyncs, double_wakeups: self.double_wakeups });
+    }
+}
@@ -1,39 +1,201 @@
+use crate::PCMMode;
+use crate::vsync;
+
+use serde::Serialize;
 use std::time;
 
-pub struct AvgPerformanceTimer {
-    avg_us : u128,
-    instant: time::Instant,
-    current_tick: u128,
-    print_every: u128,
+// Resolution of the percentiles
+const BUCKET_US: u64 = 10;
+// Times beyond this many field periods all land in the last bucket, min and max stay exact
+const HISTOGRAM_PERIODS: u64 = 4;
+const REPORT_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+// Coarse histogram printed, upper ends in percents of the field period
+const PERIOD_BINS: &'static [u64] = &[25, 50, 75, 100, 150, 200];
+
+/// The percentiles of an interval, in milliseconds
+#[derive(Copy, Clone, Serialize)]
+pub struct RenderSummary {
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub p99_9_ms: f64,
+    pub max_ms: f64,
+    /// Fields over the field period
+    pub overruns: u64
+}
+
+/// Distribution of render times against the field period, which is the budget of a field.
+/// Kept in a fine histogram, so memory stays the same for however long picm runs.
+struct RenderStats {
+    period_us: u64,
+    buckets: Vec<u64>,
+    count: u64,
+    min_us: u64,
+    max_us: u64,
+    overruns: u64
 }
 
-impl AvgPerformanceTimer {
-    pub fn new(print_every: u128) -> Self {
-        AvgPerformanceTimer {
-            avg_us: 0,
-            instant: time::Instant::now(),
-            current_tick: 0,
-            print_every: print_every
+impl RenderStats {
+    fn new(mode: &PCMMode) -> Self {
+        let (rate_num, rate_den) = vsync::get_exact_field_rate(mode);
+        let period_us = 1_000_000 * rate_den / rate_num;
+
+        RenderStats {
+            period_us: period_us,
+            buckets: vec![0; (period_us * HISTOGRAM_PERIODS / BUCKET_US) as usize + 1],
+            count: 0,
+            min_us: u64::MAX,
+            max_us: 0,
+            overruns: 0
+        }
+    }
+
+    fn add(&mut self, elapsed_us: u64) {
+        let bucket = ((elapsed_us / BUCKET_US) as usize).min(self.buckets.len() - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.min_us = self.min_us.min(elapsed_us);
+        self.max_us = self.max_us.max(elapsed_us);
+        if elapsed_us > self.period_us { self.overruns += 1; }
+    }
+
+    fn clear(&mut self) {
+        for bucket in self.buckets.iter_mut() { *bucket = 0; }
+        self.count = 0;
+        self.min_us = u64::MAX;
+        self.max_us = 0;
+        self.overruns = 0;
+    }
+
+    /// Upper end of the bucket the percentile falls in, in microseconds
+    fn percentile(&self, percent: f64) -> u64 {
+        let rank = ((self.count as f64 * percent / 100.0).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return ((bucket as u64 + 1) * BUCKET_US).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    fn summary(&self) -> Option<RenderSummary> {
+        if self.count == 0 { return None; }
+
+        let ms = |us: u64| us as f64 / 1000.0;
+        Some(RenderSummary {
+            p50_ms: ms(self.percentile(50.0)),
+            p95_ms: ms(self.percentile(95.0)),
+            p99_ms: ms(self.percentile(99.0)),
+            p99_9_ms: ms(self.percentile(99.9)),
+            max_ms: ms(self.max_us),
+            overruns: self.overruns
+        })
+    }
+
+    fn count_below(&self, limit_us: u64) -> u64 {
+        let end = ((limit_us / BUCKET_US) as usize).min(self.buckets.len());
+        self.buckets[..end].iter().sum()
+    }
+
+    fn print(&self, title: &str) {
+        if self.count == 0 { return; }
+
+        let ms = |us: u64| us as f64 / 1000.0;
+        println!("{}: {} fields, min {:.2} ms, p50 {:.2}, p95 {:.2}, p99 {:.2}, p99.9 {:.2}, max {:.2} ms, {} over the {:.1} ms budget",
+            title, self.count, ms(self.min_us), ms(self.percentile(50.0)), ms(self.percentile(95.0)), ms(self.percentile(99.0)),
+            ms(self.percentile(99.9)), ms(self.max_us), self.overruns, ms(self.period_us));
+
+        let mut bins: Vec<String> = Vec::with_capacity(PERIOD_BINS.len() + 1);
+        let mut below = 0;
+        let mut from = 0;
+        for to in PERIOD_BINS {
+            let count = self.count_below(self.period_us * to / 100);
+            bins.push(format!("{}-{}% {}", from, to, count - below));
+            below = count;
+            from = *to;
         }
+        bins.push(format!(">{}% {}", from, self.count - below));
+        println!("  of the field period: {}", bins.join(", "));
     }
+}
 
-    pub fn begin(&mut self) {
-        self.instant = time::Instant::now();
+/// Statistics of the render times of every field, of the last 10 seconds and of the whole
+/// run. Printing them is optional, they are kept for the metrics anyway. Fed by the reporting
+/// thread with the times the draw thread took, so nothing is sorted or printed there.
+pub struct RenderTimer {
+    interval: RenderStats,
+    total: RenderStats,
+    last_report: time::Instant,
+    print: bool
+}
+
+impl RenderTimer {
+    pub fn new(mode: &PCMMode, print: bool) -> Self {
+        RenderTimer {
+            interval: RenderStats::new(mode),
+            total: RenderStats::new(mode),
+            last_report: time::Instant::now(),
+            print: print
+        }
     }
 
-    pub fn end(&mut self) {
-        let elapsed_us = self.instant.elapsed().as_micros();
-        if self.avg_us > 0 {
-            self.avg_us = (self.avg_us + elapsed_us) / 2;
-        } else {
-            self.avg_us = elapsed_us;
+    /// Adds the time a field took from the wakeup to the hand over, with the summary of the
+    /// interval when one is over
+    pub fn add(&mut self, elapsed_us: u64) -> Option<RenderSummary> {
+        self.interval.add(elapsed_us);
+        self.total.add(elapsed_us);
+
+        if self.last_report.elapsed() < REPORT_INTERVAL { return None; }
+
+        if self.print {
+            self.interval.print(&format!("Render times, last {} s", REPORT_INTERVAL.as_secs()));
         }
+        let summary = self.interval.summary();
+        self.interval.clear();
+        self.last_report = time::Instant::now();
+        summary
+    }
 
-        if self.current_tick == self.print_every {
-            println!("{} us", self.avg_us);
-            self.current_tick = 0;
-        } else {
-            self.current_tick += 1;
+    pub fn print_total(&self) {
+        if self.print {
+            self.total.print("Render times, whole run");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_pcm_modes;
+
+    #[test]
+    fn percentiles_come_from_the_buckets() {
+        // PAL, 20 ms period in 10 us buckets
+        let mut stats = RenderStats::new(&get_pcm_modes()[0]);
+        assert_eq!(stats.percentile(50.0), 0);
+        assert!(stats.summary().is_none());
+
+        for _ in 0..95 { stats.add(1000); }
+        for _ in 0..4 { stats.add(5000); }
+        stats.add(12345);
+
+        // The upper end of the bucket the rank falls in
+        assert_eq!(stats.percentile(50.0), 1010);
+        assert_eq!(stats.percentile(95.0), 1010);
+        assert_eq!(stats.percentile(99.0), 5010);
+        // The bucket of the slowest one ends beyond it, its time is exact
+        assert_eq!(stats.percentile(99.9), 12345);
+        assert_eq!(stats.percentile(100.0), 12345);
+
+        let summary = stats.summary().unwrap();
+        assert_eq!((summary.p50_ms, summary.p99_ms, summary.max_ms, summary.overruns), (1.01, 5.01, 12.345, 0));
+
+        stats.add(25000);
+        assert_eq!(stats.summary().unwrap().overruns, 1);
+        stats.clear();
+        assert!(stats.summary().is_none());
+    }
+}
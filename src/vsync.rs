@@ -0,0 +1,260 @@
+use crate::PCMMode;
+use crate::report::{Event, Reporter};
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{mem, ptr, time};
+
+const NS_PER_SECOND: u128 = 1_000_000_000;
+const AUDIO_SAMPLE_RATE: f64 = 44100.0;
+
+/// Something the draw thread can wait on for the next field.
+pub trait VSyncSource: Send {
+    fn wait(&mut self) -> Vblank;
+}
+
+/// A vertical sync as the output counted and stamped it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Vblank {
+    /// Counts up by one every field, a difference of more means vsyncs were missed
+    pub sequence: u64,
+    /// When it happened, in nanoseconds of the monotonic clock
+    pub ns: u128
+}
+
+/// Field rate as a fraction, NTSC runs at 60000/1001 Hz, not 60
+pub fn get_exact_field_rate(mode: &PCMMode) -> (u64, u64) {
+    if mode.field_rate == 60 { (60000, 1001) } else { (mode.field_rate as u64, 1) }
+}
+
+pub fn get_monotonic_ns() -> u128 {
+    let mut now: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now); }
+    now.tv_sec as u128 * NS_PER_SECOND + now.tv_nsec as u128
+}
+
+/// Software vsync for outputs without a vertical sync signal. Sleeps until absolute
+/// deadlines computed from the field count, so oversleeping never accumulates. Deadlines
+/// already passed are missed like a real vsync would be, not caught up with.
+pub struct TimerVSync {
+    rate: (u64, u64),
+    start_ns: u128,
+    field: u64
+}
+
+impl TimerVSync {
+    pub fn new(mode: &PCMMode) -> Self {
+        TimerVSync {
+            rate: get_exact_field_rate(mode),
+            start_ns: get_monotonic_ns(),
+            field: 0
+        }
+    }
+}
+
+impl VSyncSource for TimerVSync {
+    fn wait(&mut self) -> Vblank {
+        let (rate_num, rate_den) = self.rate;
+        let elapsed_fields = (get_monotonic_ns() - self.start_ns) * rate_num as u128 / (rate_den as u128 * NS_PER_SECOND);
+        self.field = (self.field + 1).max(elapsed_fields as u64 + 1);
+        let deadline_ns = self.start_ns + self.field as u128 * rate_den as u128 * NS_PER_SECOND / rate_num as u128;
+
+        let deadline = libc::timespec {
+            tv_sec: (deadline_ns / NS_PER_SECOND) as libc::time_t,
+            tv_nsec: (deadline_ns % NS_PER_SECOND) as libc::c_long
+        };
+        // Restart when interrupted by a signal, the deadline is absolute
+        while unsafe { libc::clock_nanosleep(libc::CLOCK_MONOTONIC, libc::TIMER_ABSTIME, &deadline, ptr::null_mut()) } == libc::EINTR {}
+        Vblank { sequence: self.field, ns: deadline_ns }
+    }
+}
+
+/// Tells how many fields of the field clock passed between two wakeups of the draw thread:
+/// 1 normally, 0 when woken up twice in the same field, more when vsyncs were missed.
+/// Taken from the vblank counter when the output has one. Otherwise estimated from the
+/// wakeups, following the vsync phase as the field clock drifts from the system clock, and
+/// a wakeup needs to be most of a field late to count a vsync as missed: dropping a field
+/// that was not missed skips audio, so jitter of the wakeups is not taken for it.
+pub struct FieldClock {
+    period_ns: f64,
+    last_vsync_ns: Option<f64>,
+    last_sequence: Option<u64>
+}
+
+// Part of the wakeup lateness the estimated vsync time follows every field
+const PHASE_TRACKING: f64 = 1.0 / 16.0;
+// Part of a field an estimated wakeup can be late without counting a missed vsync
+const MISSED_VSYNC_LATENESS: f64 = 0.75;
+
+impl FieldClock {
+    pub fn new(mode: &PCMMode) -> Self {
+        let (rate_num, rate_den) = get_exact_field_rate(mode);
+
+        FieldClock {
+            period_ns: NS_PER_SECOND as f64 * rate_den as f64 / rate_num as f64,
+            last_vsync_ns: None,
+            last_sequence: None
+        }
+    }
+
+    /// Time of the last vsync, estimated without a vblank, in nanoseconds of the monotonic clock
+    pub fn vsync_ns(&self) -> u128 {
+        self.last_vsync_ns.unwrap_or(0.0) as u128
+    }
+
+    /// Counts the fields up to the vblank the draw thread woke up for, or up to now when
+    /// the output does not tell
+    pub fn tick(&mut self, vblank: Option<Vblank>) -> u64 {
+        match vblank {
+            Some(vblank) => self.tick_vblank(vblank),
+            None => self.tick_estimated(get_monotonic_ns() as f64)
+        }
+    }
+
+    fn tick_vblank(&mut self, vblank: Vblank) -> u64 {
+        let fields = match self.last_sequence {
+            Some(last_sequence) => vblank.sequence.saturating_sub(last_sequence),
+            None => 1
+        };
+        self.last_sequence = Some(vblank.sequence);
+        self.last_vsync_ns = Some(vblank.ns as f64);
+        fields
+    }
+
+    fn tick_estimated(&mut self, now_ns: f64) -> u64 {
+        let last_vsync_ns = match self.last_vsync_ns {
+            Some(last_vsync_ns) => last_vsync_ns,
+            None => {
+                self.last_vsync_ns = Some(now_ns);
+                return 1;
+            }
+        };
+
+        let elapsed = (now_ns - last_vsync_ns) / self.period_ns;
+        let fields = if elapsed < 0.5 { 0.0 } else { (elapsed + 1.0 - MISSED_VSYNC_LATENESS).floor().max(1.0) };
+        let vsync_ns = last_vsync_ns + fields * self.period_ns;
+        self.last_vsync_ns = Some(vsync_ns + (now_ns - vsync_ns) * PHASE_TRACKING);
+
+        fields as u64
+    }
+}
+
+/// Audio speed correction shared between the draw thread measuring the field clock and
+/// the producer thread resampling the audio.
+#[derive(Clone)]
+pub struct RateCorrection {
+    ratio: Arc<AtomicU64>
+}
+
+impl RateCorrection {
+    pub fn new() -> Self {
+        RateCorrection { ratio: Arc::new(AtomicU64::new(1.0f64.to_bits())) }
+    }
+
+    /// Output samples per input sample
+    pub fn get_ratio(&self) -> f64 {
+        f64::from_bits(self.ratio.load(Ordering::Relaxed))
+    }
+
+    fn set_ratio(&self, ratio: f64) {
+        self.ratio.store(ratio.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Measures the field clock against the system clock, and from that the rate the encoded
+/// audio actually plays at, versus the 44.1kHz it was sampled at.
+pub struct DriftMeter {
+    samples_per_field: f64,
+    start: Option<time::Instant>,
+    fields: u64,
+    last_report: time::Instant,
+    correction: Option<RateCorrection>,
+    ratio: Option<f64>,
+    reporter: Reporter
+}
+
+const DRIFT_SETTLE_FIELDS: u64 = 250;
+const DRIFT_REPORT_INTERVAL: time::Duration = time::Duration::from_secs(10);
+const MAX_CORRECTION: f64 = 0.01;
+
+impl DriftMeter {
+    pub fn new(mode: &PCMMode, correction: Option<RateCorrection>, reporter: Reporter) -> Self {
+        DriftMeter {
+            // Every line carries three stereo samples
+            samples_per_field: (mode.pcm_data_lines_in_field * 3) as f64,
+            start: None,
+            fields: 0,
+            last_report: time::Instant::now(),
+            correction: correction,
+            ratio: None,
+            reporter: reporter
+        }
+    }
+
+    pub fn field(&mut self) {
+        let now = time::Instant::now();
+        let start = match self.start {
+            Some(start) => start,
+            None => {
+                self.start = Some(now);
+                return;
+            }
+        };
+
+        self.fields += 1;
+        if self.fields < DRIFT_SETTLE_FIELDS { return; }
+
+        let field_rate = self.fields as f64 / (now - start).as_secs_f64();
+        let sample_rate = field_rate * self.samples_per_field;
+        let ratio = sample_rate / AUDIO_SAMPLE_RATE;
+        self.ratio = Some(ratio);
+
+        if let Some(correction) = &self.correction {
+            correction.set_ratio(ratio.clamp(1.0 - MAX_CORRECTION, 1.0 + MAX_CORRECTION));
+        }
+
+        if now - self.last_report >= DRIFT_REPORT_INTERVAL {
+            self.reporter.send(Event::Drift { field_rate: field_rate, sample_rate: sample_rate, ppm: (ratio - 1.0) * 1e6,
+                corrected: self.correction.is_some() });
+            self.last_report = now;
+        }
+    }
+
+    /// How much faster the audio plays than it was sampled, once measured
+    pub fn drift_ppm(&self) -> Option<f64> {
+        self.ratio.map(|ratio| (ratio - 1.0) * 1e6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_pcm_modes;
+
+    #[test]
+    fn vblank_counter_tells_the_fields() {
+        let mut clock = FieldClock::new(&get_pcm_modes()[0]);
+        let vblank = |sequence: u64| Some(Vblank { sequence: sequence, ns: sequence as u128 * 20_000_000 });
+
+        let fields: Vec<u64> = [100, 101, 101, 102, 105, 106].iter().map(|sequence| clock.tick(vblank(*sequence))).collect();
+        assert_eq!(fields, [1, 1, 0, 1, 3, 1]);
+        assert_eq!(clock.vsync_ns(), 106 * 20_000_000);
+    }
+
+    #[test]
+    fn late_wakeups_are_not_taken_for_missed_vsyncs() {
+        let mut clock = FieldClock::new(&get_pcm_modes()[0]);
+        let period = 20_000_000.0;
+        let mut tick_at = |fields: f64| clock.tick_estimated(1e12 + fields * period);
+
+        assert_eq!(tick_at(0.0), 1);
+        // Jitter either way, up to most of a field late
+        assert_eq!(tick_at(1.0), 1);
+        assert_eq!(tick_at(1.3), 0);
+        assert_eq!(tick_at(2.7), 1);
+        assert_eq!(tick_at(3.0), 1);
+        // A vsync missed on the wakeup later than that, the next one on time
+        assert_eq!(tick_at(4.9), 2);
+        assert_eq!(tick_at(6.0), 1);
+    }
+}